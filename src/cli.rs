@@ -0,0 +1,668 @@
+//! cryptkeeper, the command line side of the password manager, for scripting lookups on machines without a display
+//! Every command prints JSON on stdout, errors go to stderr with a non-zero exit code
+use password_manager::entry::{self, Entry, EntryData};
+use password_manager::otp::OtpCounter;
+use password_manager::password_generator;
+use password_manager::secret_memory::SecretString;
+use password_manager::storage_options_sql::{self, AuthError, Vault};
+use password_manager::vault_cache::VaultCache;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, IsTerminal, Write};
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: cryptkeeper [--vault <path>] [--user <name>] <command> [arguments]
+
+Commands:
+  init                     Create the account given with --user
+  unlock                   Check the master password, nothing stays unlocked between commands
+  add                      Add a login, with --title, --account, --website, --notes, --url, --tags <a,b>
+                           The password is asked for, or made with --generate [--length <n>]
+  get <entry>              Show an entry with its password, --field <name> prints just one field
+                           (title, account, website, password, notes or otp)
+  edit <entry>             Change the fields given like add, --password asks for a new password
+  rm <entry>               Move an entry to the trash, --purge deletes it for good
+  ls                       List every entry, without passwords
+  search <query>           List the entries matching a query, without passwords
+  generate                 Make a random password, --length <n> (20 by default)
+  passwd                   Change the master password
+  export                   Print every entry with its password, or write them to --output <file>
+
+An entry is its id, or a search query that matches exactly one entry.
+The user can also be given with the CRYPTKEEPER_USER environment variable, and the vault with CRYPTKEEPER_VAULT.
+Passwords are asked for on the terminal, or read from stdin a line at a time when it isn't one.";
+
+/// The environment variable the account can be given in instead of --user
+const USER_ENV_VAR: &str = "CRYPTKEEPER_USER";
+
+/// Options that don't take a value
+const FLAGS: [&str; 4] = ["generate", "password", "purge", "help"];
+
+/// The length of generated passwords unless --length is given, the same as the generate button in the app
+const DEFAULT_PASSWORD_LENGTH: u32 = 20;
+
+/// The command line split into the command, its positional arguments and its options
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    command: Option<String>,
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+    flags: Vec<String>,
+}
+
+impl Args {
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|flag| flag == name)
+    }
+
+    /// The one positional argument a command takes, like the entry of get
+    fn argument(&self, what: &str) -> Result<&str, String> {
+        match self.positional.as_slice() {
+            [argument] => Ok(argument),
+            [] => Err(format!("Missing the {}", what)),
+            _ => Err(format!("Expected a single {}, quote it if it has spaces", what)),
+        }
+    }
+
+    fn user(&self) -> Result<String, String> {
+        self.option("user")
+            .map(str::to_string)
+            .or_else(|| std::env::var(USER_ENV_VAR).ok().filter(|user| !user.is_empty()))
+            .ok_or_else(|| format!("No user given, use --user <name> or set {}", USER_ENV_VAR))
+    }
+
+    fn length(&self) -> Result<u32, String> {
+        match self.option("length") {
+            Some(length) => length.parse().ok().filter(|length| *length > 0).ok_or_else(|| format!("Invalid length {}", length)),
+            None => Ok(DEFAULT_PASSWORD_LENGTH),
+        }
+    }
+}
+
+/// Split the command line, options are --name value or --name=value and can go anywhere
+fn parse_args(mut raw: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut args = Args::default();
+    while let Some(arg) = raw.next() {
+        let Some(name) = arg.strip_prefix("--") else {
+            match args.command {
+                None => args.command = Some(arg),
+                Some(_) => args.positional.push(arg),
+            }
+            continue;
+        };
+        if let Some((name, value)) = name.split_once('=') {
+            args.options.insert(name.to_string(), value.to_string());
+        } else if FLAGS.contains(&name) {
+            args.flags.push(name.to_string());
+        } else {
+            let value = raw.next().ok_or_else(|| format!("--{} needs a value", name))?;
+            args.options.insert(name.to_string(), value);
+        }
+    }
+    Ok(args)
+}
+
+/// What a command prints, JSON unless a single field was asked for
+#[derive(Debug)]
+enum Output {
+    Json(Value),
+    Text(SecretString),
+}
+
+/// Where passwords are read from, a terminal is prompted without echoing what is typed
+/// Anything else is read a line at a time, so scripts can pipe the master password in, followed by any other password the command needs
+enum Passwords {
+    Terminal,
+    Lines(Box<dyn BufRead>),
+}
+
+impl Passwords {
+    fn new() -> Self {
+        if std::io::stdin().is_terminal() {
+            Passwords::Terminal
+        } else {
+            Passwords::Lines(Box::new(std::io::stdin().lock()))
+        }
+    }
+
+    fn is_terminal(&self) -> bool {
+        matches!(self, Passwords::Terminal)
+    }
+
+    fn read(&mut self, prompt: &str) -> Result<SecretString, String> {
+        if self.is_terminal() {
+            eprint!("{}: ", prompt);
+            let _ = std::io::stderr().flush();
+        }
+        // Enough room for any sensible password, so the line isn't moved around the heap while it is read
+        let mut line = String::with_capacity(256);
+        let read = match self {
+            Passwords::Terminal => read_hidden_line(&mut line),
+            Passwords::Lines(input) => input.read_line(&mut line),
+        };
+        let mut password = SecretString::from(line);
+        match read {
+            Ok(0) => return Err(format!("No input for the {}", prompt.to_lowercase())),
+            Ok(_) => {}
+            Err(error) => return Err(format!("Could not read the {}: {}", prompt.to_lowercase(), error)),
+        }
+        let trimmed = password.expose().trim_end_matches(['\r', '\n']).len();
+        if trimmed < password.expose().len() {
+            let without_newline = SecretString::from(&password.expose()[..trimmed]);
+            password = without_newline;
+        }
+        Ok(password)
+    }
+
+    /// Read a new password, typed twice on a terminal, scripts only give it once
+    fn read_new(&mut self, prompt: &str) -> Result<(SecretString, SecretString), String> {
+        let password = self.read(prompt)?;
+        let confirmation = if self.is_terminal() { self.read(&format!("Confirm the {}", prompt.to_lowercase()))? } else { password.clone() };
+        Ok((password, confirmation))
+    }
+}
+
+/// Read a line from the terminal with echo turned off, so the password isn't shown or left in the scrollback
+#[cfg(unix)]
+fn read_hidden_line(line: &mut String) -> std::io::Result<usize> {
+    let fd = libc::STDIN_FILENO;
+    let mut original = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut original) } != 0 {
+        return std::io::stdin().read_line(line);
+    }
+    let mut hidden = original;
+    hidden.c_lflag &= !libc::ECHO;
+    hidden.c_lflag |= libc::ECHONL;
+    unsafe {
+        libc::tcsetattr(fd, libc::TCSANOW, &hidden);
+    }
+    let read = std::io::stdin().read_line(line);
+    unsafe {
+        libc::tcsetattr(fd, libc::TCSANOW, &original);
+    }
+    read
+}
+
+/// Read a line from the console with echo turned off, so the password isn't shown or left in the scrollback
+#[cfg(windows)]
+fn read_hidden_line(line: &mut String) -> std::io::Result<usize> {
+    use winapi::um::consoleapi::{GetConsoleMode, SetConsoleMode};
+    use winapi::um::processenv::GetStdHandle;
+    use winapi::um::winbase::STD_INPUT_HANDLE;
+    use winapi::um::wincon::ENABLE_ECHO_INPUT;
+
+    let handle = unsafe { GetStdHandle(STD_INPUT_HANDLE) };
+    let mut original = 0;
+    if unsafe { GetConsoleMode(handle, &mut original) } == 0 {
+        return std::io::stdin().read_line(line);
+    }
+    unsafe {
+        SetConsoleMode(handle, original & !ENABLE_ECHO_INPUT);
+    }
+    let read = std::io::stdin().read_line(line);
+    unsafe {
+        SetConsoleMode(handle, original);
+    }
+    // The enter key isn't echoed either
+    eprintln!();
+    read
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |time| time.as_secs())
+}
+
+/// Unlock the vault of the user given on the command line with their master password
+fn unlock(vault: &Vault, args: &Args, passwords: &mut Passwords) -> Result<VaultCache, String> {
+    let user = args.user()?;
+    let master_password = passwords.read(&format!("Master password for {}", user))?;
+    match vault.verify_master(&user, master_password.expose()) {
+        Ok(unlocked) => {
+            // Standard output is for the JSON, so the warning goes to standard error
+            if let Some(error) = unlocked.upgrade_error {
+                eprintln!("Warning: could not upgrade the account to Argon2id, it will be tried again next time: {}", error);
+            }
+            Ok(VaultCache::load(vault, unlocked))
+        }
+        Err(AuthError::LockedOut(remaining)) => Err(format!("The account is locked for another {} seconds after too many wrong master passwords", remaining)),
+        Err(error) => Err(format!("Could not unlock the vault: {}", error)),
+    }
+}
+
+/// Find an entry by its id, or by a search that only matches that one entry
+fn find_entry<'a>(cache: &'a VaultCache, query: &str) -> Result<&'a Entry, String> {
+    if let Ok(entry_id) = query.parse() {
+        return cache.find(entry_id).ok_or_else(|| format!("There is no entry {}", entry_id));
+    }
+    match cache.search(query).as_slice() {
+        [index] => Ok(cache.entry(*index)),
+        [] => Err(format!("No entry matches {}", query)),
+        matches => Err(format!("{} entries match {}, use the id of the one you want", matches.len(), query)),
+    }
+}
+
+/// The parts of an entry that are listed, everything but the password and the other secrets
+fn summary(entry: &Entry) -> Value {
+    let details = entry.details.as_ref().ok();
+    json!({
+        "id": entry.id,
+        "kind": entry.kind.name(),
+        "name": entry.display_name(),
+        "title": details.map(|details| details.title.expose()),
+        "account": entry.account.as_ref().ok().map(SecretString::expose),
+        "website": entry.website.as_ref().ok().map(SecretString::expose),
+        "tags": details.map(|details| details.tags.iter().map(SecretString::expose).collect::<Vec<_>>()),
+        "favorite": entry.favorite,
+        "folder_id": entry.folder_id,
+        "modified_at": entry.modified_at,
+    })
+}
+
+/// Everything in an entry, including its password and the current two-factor code of TOTP secrets
+fn full(entry: &Entry) -> Result<Value, String> {
+    let data = entry.data().map_err(|error| format!("Entry {} could not be decrypted: {}", entry.id, error))?;
+    let otp_code = data.details.otp.as_ref()
+        .filter(|otp| matches!(otp.counter, OtpCounter::Time { .. }))
+        .map(|otp| json!({ "code": otp.code(unix_now()).expose(), "seconds_remaining": otp.seconds_remaining(unix_now()) }));
+    Ok(json!({
+        "id": entry.id,
+        "kind": entry.kind.name(),
+        "account": data.account,
+        "website": data.website,
+        "password": data.password,
+        "details": data.details,
+        "payload": data.payload,
+        "otp_code": otp_code,
+        "favorite": data.favorite,
+        "folder_id": data.folder_id,
+        "created_at": entry.created_at,
+        "modified_at": entry.modified_at,
+        "last_used_at": entry.last_used_at,
+    }))
+}
+
+/// Apply the field options of add and edit to an entry, anything not given is left as it is
+fn apply_fields(data: &mut EntryData, args: &Args) {
+    if let Some(title) = args.option("title") {
+        data.details.title = SecretString::from(title);
+    }
+    if let Some(account) = args.option("account") {
+        data.account = SecretString::from(account);
+    }
+    if let Some(website) = args.option("website") {
+        data.website = SecretString::from(website);
+    }
+    if let Some(notes) = args.option("notes") {
+        data.details.notes = SecretString::from(notes);
+    }
+    if let Some(url) = args.option("url") {
+        data.details.urls = vec![SecretString::from(url)];
+    }
+    if let Some(tags) = args.option("tags") {
+        data.details.tags = entry::parse_tags(tags);
+    }
+}
+
+/// What add and edit print, a generated password is included since the script has no other way of finding out what it is
+fn changed_entry(entry_id: i32, generated: Option<&SecretString>) -> Value {
+    let mut output = json!({ "id": entry_id });
+    if let Some(password) = generated {
+        output["password"] = json!(password);
+    }
+    output
+}
+
+/// Check a new master password like the app does, against the minimum strength of the vault
+fn check_new_master(vault: &Vault, password: &SecretString, confirmation: &SecretString) -> Result<(), String> {
+    password_generator::check_new_master_password(password.expose(), confirmation.expose(), vault.min_master_score())
+}
+
+fn run(args: &Args, passwords: &mut Passwords) -> Result<Output, String> {
+    if args.flag("help") {
+        return Ok(Output::Text(SecretString::from(USAGE)));
+    }
+    let command = args.command.as_deref().ok_or_else(|| format!("No command given\n\n{}", USAGE))?;
+    // Generating doesn't need a vault at all
+    if command == "generate" {
+        let password = SecretString::from(password_generator::generate_password(args.length()?));
+        let (safety_rating, _) = password_generator::check_password_safety(password.expose());
+        return Ok(Output::Json(json!({ "password": password, "safety_rating": safety_rating })));
+    }
+
+    let vault_path = args.option("vault").map(std::path::PathBuf::from).unwrap_or_else(storage_options_sql::default_vault_path);
+    let vault = Vault::open(&vault_path).map_err(|error| format!("Failed to open the vault at {}: {}", vault_path.display(), error))?;
+
+    match command {
+        "init" => {
+            let user = args.user()?;
+            let (password, confirmation) = passwords.read_new(&format!("New master password for {}", user))?;
+            check_new_master(&vault, &password, &confirmation)?;
+            let user_id = vault.add_user_id(&user, password.expose()).map_err(|error| format!("Could not create the account: {}", error))?;
+            Ok(Output::Json(json!({ "user": user, "user_id": user_id, "vault": vault.path() })))
+        }
+        "unlock" => {
+            let cache = unlock(&vault, args, passwords)?;
+            Ok(Output::Json(json!({ "user": args.user()?, "entries": cache.entry_count(), "trash": cache.trash().len() })))
+        }
+        "add" => {
+            let mut cache = unlock(&vault, args, passwords)?;
+            let mut data = EntryData::default();
+            apply_fields(&mut data, args);
+            let generated = args.flag("generate");
+            data.password = if generated {
+                SecretString::from(password_generator::generate_password(args.length()?))
+            } else {
+                passwords.read("Password for the entry")?
+            };
+            let entry_id = cache.add(&vault, &data).map_err(|error| format!("Could not add the entry: {}", error))?;
+            Ok(Output::Json(changed_entry(entry_id, generated.then_some(&data.password))))
+        }
+        "get" => {
+            let mut cache = unlock(&vault, args, passwords)?;
+            let entry = find_entry(&cache, args.argument("entry")?)?;
+            let entry_id = entry.id;
+            let output = match args.option("field") {
+                None => Output::Json(full(entry)?),
+                Some(field) => {
+                    let mut data = entry.data().map_err(|error| format!("Entry {} could not be decrypted: {}", entry_id, error))?;
+                    Output::Text(match field {
+                        "title" => data.details.title,
+                        "account" => data.account,
+                        "website" => data.website,
+                        "password" => data.password,
+                        "notes" => data.details.notes,
+                        "otp" => {
+                            let otp = data.details.otp.as_mut().ok_or_else(|| format!("Entry {} has no two-factor secret", entry_id))?;
+                            let code = otp.code(unix_now());
+                            // HOTP codes use up the counter, which is saved before the code is handed out like in the app
+                            if let OtpCounter::Counter(counter) = &mut otp.counter {
+                                *counter += 1;
+                                cache.update(&vault, entry_id, &data).map_err(|error| format!("The two-factor counter could not be saved: {}", error))?;
+                            }
+                            code
+                        }
+                        _ => return Err(format!("Unknown field {}, it can be title, account, website, password, notes or otp", field)),
+                    })
+                }
+            };
+            cache.mark_used(&vault, entry_id);
+            Ok(output)
+        }
+        "edit" => {
+            let mut cache = unlock(&vault, args, passwords)?;
+            let entry = find_entry(&cache, args.argument("entry")?)?;
+            let entry_id = entry.id;
+            let mut data = entry.data().map_err(|error| format!("Entry {} could not be decrypted: {}", entry_id, error))?;
+            apply_fields(&mut data, args);
+            let generated = args.flag("generate");
+            if generated {
+                data.password = SecretString::from(password_generator::generate_password(args.length()?));
+            } else if args.flag("password") {
+                data.password = passwords.read("New password for the entry")?;
+            }
+            cache.update(&vault, entry_id, &data).map_err(|error| format!("Could not change the entry: {}", error))?;
+            Ok(Output::Json(changed_entry(entry_id, generated.then_some(&data.password))))
+        }
+        "rm" => {
+            let mut cache = unlock(&vault, args, passwords)?;
+            let entry_id = find_entry(&cache, args.argument("entry")?)?.id;
+            cache.trash_entry(&vault, entry_id).map_err(|error| format!("Could not delete the entry: {}", error))?;
+            let purge = args.flag("purge");
+            if purge {
+                cache.purge(&vault, entry_id).map_err(|error| format!("Could not delete the entry: {}", error))?;
+            }
+            Ok(Output::Json(json!({ "id": entry_id, "purged": purge })))
+        }
+        "ls" | "search" => {
+            let cache = unlock(&vault, args, passwords)?;
+            let query = if command == "search" { args.argument("query")? } else { "" };
+            let entries: Vec<Value> = cache.search(query).into_iter().map(|index| summary(cache.entry(index))).collect();
+            Ok(Output::Json(Value::Array(entries)))
+        }
+        "passwd" => {
+            let cache = unlock(&vault, args, passwords)?;
+            let (password, confirmation) = passwords.read_new("New master password")?;
+            check_new_master(&vault, &password, &confirmation)?;
+            vault.change_master_password(cache.user_id(), cache.data_key(), password.expose())
+                .map_err(|error| format!("Could not change the master password: {}", error))?;
+            Ok(Output::Json(json!({ "user": args.user()?, "changed": true })))
+        }
+        "export" => {
+            let cache = unlock(&vault, args, passwords)?;
+            // Entries that can't be decrypted are listed with the error instead of silently leaving them out
+            let entries: Vec<Value> = cache.search("").into_iter()
+                .map(|index| cache.entry(index))
+                .map(|entry| full(entry).unwrap_or_else(|error| json!({ "id": entry.id, "error": error })))
+                .collect();
+            let Some(path) = args.option("output") else {
+                return Ok(Output::Json(Value::Array(entries)));
+            };
+            write_export(std::path::Path::new(path), &Value::Array(entries))?;
+            eprintln!("Warning: {} holds every password in plain text, delete it once you are done with it", path);
+            Ok(Output::Json(json!({ "exported": cache.entry_count(), "path": path })))
+        }
+        _ => Err(format!("Unknown command {}\n\n{}", command, USAGE)),
+    }
+}
+
+/// Write an export to a new file only the user can read, an existing file is never overwritten
+fn write_export(path: &std::path::Path, entries: &Value) -> Result<(), String> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).map_err(|error| format!("Could not create {}: {}", path.display(), error))?;
+    serde_json::to_writer_pretty(&mut file, entries)
+        .map_err(std::io::Error::from)
+        .and_then(|()| file.write_all(b"\n"))
+        .map_err(|error| format!("Could not write {}: {}", path.display(), error))
+}
+
+fn main() -> ExitCode {
+    let output = match parse_args(std::env::args().skip(1)).and_then(|args| run(&args, &mut Passwords::new())) {
+        Ok(output) => output,
+        Err(message) => {
+            eprintln!("cryptkeeper: {}", message);
+            return ExitCode::FAILURE;
+        }
+    };
+    let text = match output {
+        Output::Json(value) => SecretString::from(serde_json::to_string_pretty(&value).expect("Failed to serialize output")),
+        Output::Text(text) => text,
+    };
+    // Output piped into something like head that stops reading early isn't an error
+    match writeln!(std::io::stdout(), "{}", text.expose()) {
+        Err(error) if error.kind() != std::io::ErrorKind::BrokenPipe => {
+            eprintln!("cryptkeeper: Could not write the output: {}", error);
+            ExitCode::FAILURE
+        }
+        _ => ExitCode::SUCCESS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A master password strong enough for the minimum score of a new vault
+    const MASTER: &str = "Correct-Horse-Battery-Staple-42";
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    /// Run a command as alice against the vault in a directory, with the passwords it reads piped in like a script would
+    fn cryptkeeper(directory: &tempfile::TempDir, command: &[&str], stdin: &str) -> Result<Output, String> {
+        let vault = directory.path().join("passwords.db");
+        let mut args = parse(command)?;
+        args.options.insert("vault".to_string(), vault.to_string_lossy().into_owned());
+        args.options.insert("user".to_string(), "alice".to_string());
+        run(&args, &mut Passwords::Lines(Box::new(std::io::Cursor::new(stdin.to_string()))))
+    }
+
+    /// Run a command that prints JSON, failing the test if it doesn't
+    fn cryptkeeper_json(directory: &tempfile::TempDir, command: &[&str], stdin: &str) -> Value {
+        match cryptkeeper(directory, command, stdin).unwrap() {
+            Output::Json(value) => value,
+            Output::Text(_) => panic!("{:?} printed text instead of JSON", command),
+        }
+    }
+
+    /// Create a vault with an account for alice
+    fn init_vault() -> tempfile::TempDir {
+        let directory = tempfile::tempdir().unwrap();
+        let created = cryptkeeper_json(&directory, &["init"], &format!("{}\n", MASTER));
+        assert_eq!(created["user"], "alice");
+        directory
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = parse(&["--vault", "work.db", "get", "github", "--field=password", "--user", "alice"]).unwrap();
+        assert_eq!(args.command.as_deref(), Some("get"));
+        assert_eq!(args.positional, vec!["github"]);
+        assert_eq!(args.option("vault"), Some("work.db"));
+        assert_eq!(args.option("field"), Some("password"));
+        assert_eq!(args.user(), Ok("alice".to_string()));
+
+        let args = parse(&["edit", "3", "--generate", "--length", "32"]).unwrap();
+        assert!(args.flag("generate") && !args.flag("purge"));
+        assert_eq!(args.length(), Ok(32));
+        assert_eq!(parse(&["generate"]).unwrap().length(), Ok(DEFAULT_PASSWORD_LENGTH));
+        assert!(parse(&["generate", "--length", "0"]).unwrap().length().is_err());
+
+        assert_eq!(parse(&["get", "--user"]).unwrap_err(), "--user needs a value");
+        assert!(parse(&["search", "two", "words"]).unwrap().argument("query").is_err());
+    }
+
+    #[test]
+    fn test_find_entry() {
+        let directory = tempfile::tempdir().unwrap();
+        let vault = Vault::open(directory.path().join("passwords.db")).unwrap();
+        vault.add_user_id("user", "supersecret").unwrap();
+        let mut cache = VaultCache::load(&vault, vault.verify_master("user", "supersecret").unwrap());
+        let github = cache.add(&vault, &EntryData::new("alice", "password", "github.com")).unwrap();
+        cache.add(&vault, &EntryData::new("alice", "password", "gitlab.com")).unwrap();
+
+        // Ids and queries that match a single entry find it, anything else says why it didn't
+        assert_eq!(find_entry(&cache, &github.to_string()).unwrap().id, github);
+        assert_eq!(find_entry(&cache, "GitHub").unwrap().id, github);
+        assert_eq!(find_entry(&cache, "git").unwrap_err(), "2 entries match git, use the id of the one you want");
+        assert!(find_entry(&cache, "bitbucket").is_err());
+        assert!(find_entry(&cache, "999").is_err());
+
+        // Listing leaves the password out
+        let listed = summary(cache.find(github).unwrap());
+        assert_eq!(listed["website"], "github.com");
+        assert!(listed.get("password").is_none());
+        assert_eq!(full(cache.find(github).unwrap()).unwrap()["password"], "password");
+    }
+
+    #[test]
+    fn test_init_and_unlock() {
+        let directory = tempfile::tempdir().unwrap();
+        assert!(cryptkeeper(&directory, &["init"], "weak\n").unwrap_err().contains("too weak"));
+        assert!(cryptkeeper(&directory, &["init"], "").unwrap_err().contains("No input"));
+
+        let created = cryptkeeper_json(&directory, &["init"], &format!("{}\n", MASTER));
+        assert_eq!(created["vault"], directory.path().join("passwords.db").to_str().unwrap());
+        assert!(cryptkeeper(&directory, &["init"], &format!("{}\n", MASTER)).is_err());
+
+        assert_eq!(cryptkeeper_json(&directory, &["unlock"], &format!("{}\n", MASTER)), json!({ "user": "alice", "entries": 0, "trash": 0 }));
+        assert!(cryptkeeper(&directory, &["unlock"], "wrong\n").unwrap_err().starts_with("Could not unlock the vault"));
+    }
+
+    #[test]
+    fn test_entry_commands() {
+        let directory = init_vault();
+        let master = format!("{}\n", MASTER);
+
+        // The password of the entry is the line after the master password, unless it is generated
+        let added = cryptkeeper_json(&directory, &["add", "--title", "GitHub", "--account", "alice", "--website", "github.com", "--tags", "work, code"], &format!("{}hunter2\n", master));
+        let github = added["id"].as_i64().unwrap();
+        assert_eq!(added, json!({ "id": github }));
+        let generated = cryptkeeper_json(&directory, &["add", "--website", "gitlab.com", "--generate", "--length", "32"], &master);
+        assert_eq!(generated["password"].as_str().unwrap().len(), 32);
+
+        let entry = cryptkeeper_json(&directory, &["get", "GitHub"], &master);
+        assert_eq!((entry["id"].as_i64(), &entry["account"], &entry["password"]), (Some(github), &json!("alice"), &json!("hunter2")));
+        assert_eq!(entry["details"]["title"], "GitHub");
+        assert_eq!(entry["details"]["tags"], json!(["work", "code"]));
+        assert!(entry["last_used_at"].is_null());
+        let Output::Text(password) = cryptkeeper(&directory, &["get", "github", "--field", "password"], &master).unwrap() else {
+            panic!("--field printed JSON");
+        };
+        assert_eq!(password.expose(), "hunter2");
+        assert!(cryptkeeper(&directory, &["get", "git"], &master).unwrap_err().contains("2 entries match"));
+
+        // Editing only changes what was given, and the password is asked for with --password
+        let edited = cryptkeeper_json(&directory, &["edit", &github.to_string(), "--password", "--notes", "Has 2FA"], &format!("{}hunter3\n", master));
+        assert_eq!(edited, json!({ "id": github }));
+        let entry = cryptkeeper_json(&directory, &["get", "github.com"], &master);
+        assert_eq!((&entry["password"], &entry["details"]["notes"], &entry["details"]["title"]), (&json!("hunter3"), &json!("Has 2FA"), &json!("GitHub")));
+        assert!(entry["last_used_at"].is_number());
+
+        // Listing and searching never show passwords
+        let listed = cryptkeeper_json(&directory, &["ls"], &master);
+        assert_eq!(listed.as_array().unwrap().len(), 2);
+        assert!(listed.as_array().unwrap().iter().all(|entry| entry.get("password").is_none()));
+        let found = cryptkeeper_json(&directory, &["search", "gitlab"], &master);
+        assert_eq!(found.as_array().unwrap().len(), 1);
+        assert_eq!(found[0]["website"], "gitlab.com");
+
+        // rm moves to the trash, --purge deletes for good
+        let gitlab = found[0]["id"].as_i64().unwrap();
+        assert_eq!(cryptkeeper_json(&directory, &["rm", "gitlab"], &master), json!({ "id": gitlab, "purged": false }));
+        assert_eq!(cryptkeeper_json(&directory, &["unlock"], &master), json!({ "user": "alice", "entries": 1, "trash": 1 }));
+        assert_eq!(cryptkeeper_json(&directory, &["rm", "github", "--purge"], &master), json!({ "id": github, "purged": true }));
+        assert_eq!(cryptkeeper_json(&directory, &["unlock"], &master), json!({ "user": "alice", "entries": 0, "trash": 1 }));
+        assert!(cryptkeeper(&directory, &["get", &github.to_string()], &master).unwrap_err().contains("There is no entry"));
+    }
+
+    #[test]
+    fn test_export_and_passwd() {
+        let directory = init_vault();
+        let master = format!("{}\n", MASTER);
+        cryptkeeper_json(&directory, &["add", "--website", "github.com"], &format!("{}hunter2\n", master));
+
+        // Exports include the passwords, and a file is only written if it doesn't exist yet
+        let exported = cryptkeeper_json(&directory, &["export"], &master);
+        assert_eq!(exported[0]["password"], "hunter2");
+        let path = directory.path().join("export.json");
+        let path_arg = path.to_str().unwrap();
+        assert_eq!(cryptkeeper_json(&directory, &["export", "--output", path_arg], &master), json!({ "exported": 1, "path": path_arg }));
+        let written: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written, exported);
+        #[cfg(unix)]
+        assert_eq!(std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(&path).unwrap().permissions()) & 0o777, 0o600);
+        assert!(cryptkeeper(&directory, &["export", "--output", path_arg], &master).unwrap_err().starts_with("Could not create"));
+
+        // After changing the master password only the new one unlocks, and the entries are still there
+        let new_master = "Another-Correct-Horse-Battery-99";
+        assert!(cryptkeeper(&directory, &["passwd"], &format!("{}weak\n", master)).unwrap_err().contains("too weak"));
+        assert_eq!(cryptkeeper_json(&directory, &["passwd"], &format!("{}{}\n", master, new_master)), json!({ "user": "alice", "changed": true }));
+        assert!(cryptkeeper(&directory, &["unlock"], &master).is_err());
+        let entry = cryptkeeper_json(&directory, &["get", "github"], &format!("{}\n", new_master));
+        assert_eq!(entry["password"], "hunter2");
+    }
+
+    #[test]
+    fn test_generate_and_errors() {
+        // Generating works without a vault or a user
+        let Output::Json(generated) = run(&parse(&["generate", "--length", "24"]).unwrap(), &mut Passwords::Lines(Box::new(std::io::empty()))).unwrap() else {
+            panic!("generate printed text");
+        };
+        assert_eq!(generated["password"].as_str().unwrap().len(), 24);
+        assert!(generated["safety_rating"].is_number());
+
+        let directory = init_vault();
+        assert!(cryptkeeper(&directory, &["frobnicate"], "").unwrap_err().starts_with("Unknown command frobnicate"));
+        assert!(cryptkeeper(&directory, &["get"], &format!("{}\n", MASTER)).unwrap_err().contains("Missing the entry"));
+        // A script that forgets the entry's password gets an error instead of an empty password
+        assert!(cryptkeeper(&directory, &["add", "--website", "github.com"], &format!("{}\n", MASTER)).unwrap_err().contains("No input"));
+    }
+}
@@ -0,0 +1,110 @@
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+/// The system clipboard, which forgets what was copied from the vault after a while
+/// Only a hash of the copied value is kept, so the clipboard is only cleared if it still holds our value and not something copied since
+pub struct Clipboard {
+    /// Opened on the first copy, since there may be no clipboard at all, like on a display server without one
+    clipboard: Option<arboard::Clipboard>,
+    copied: Option<Zeroizing<[u8; 32]>>,
+    clears_at: Option<Instant>,
+    /// Bumped on every copy, so the timer of an earlier copy doesn't clear a newer one that happens to be the same value
+    generation: Arc<AtomicU64>,
+}
+
+/// Hash what the clipboard holds, to compare it with what was copied without keeping a copy of the secret around
+fn fingerprint(text: &str) -> Zeroizing<[u8; 32]> {
+    Zeroizing::new(Sha256::digest(text.as_bytes()).into())
+}
+
+/// Check whether the clipboard still holds what was copied, anything that isn't text can't be
+fn holds(clipboard: &mut arboard::Clipboard, copied: &[u8; 32]) -> bool {
+    clipboard.get_text().is_ok_and(|text| *fingerprint(&Zeroizing::new(text)) == *copied)
+}
+
+impl Clipboard {
+    pub fn new() -> Self {
+        Self {
+            clipboard: None,
+            copied: None,
+            clears_at: None,
+            generation: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Copy a value, clearing it again after the timeout unless something else was copied since, a timeout of 0 never clears it
+    /// The timer runs on its own thread, so the value is cleared even when the window is minimized and not being repainted
+    pub fn copy(&mut self, text: &str, timeout: u32) -> Result<(), arboard::Error> {
+        if self.clipboard.is_none() {
+            self.clipboard = Some(arboard::Clipboard::new()?);
+        }
+        let clipboard = self.clipboard.as_mut().expect("Clipboard was just opened");
+        clipboard.set_text(text)?;
+
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let copied = fingerprint(text);
+        self.copied = Some(copied.clone());
+        self.clears_at = None;
+        if timeout == 0 {
+            return Ok(());
+        }
+
+        let timeout = Duration::from_secs(timeout.into());
+        self.clears_at = Some(Instant::now() + timeout);
+        let current_generation = Arc::clone(&self.generation);
+        std::thread::spawn(move || {
+            std::thread::sleep(timeout);
+            if current_generation.load(Ordering::SeqCst) != generation {
+                return;
+            }
+            // Nothing can be shown from here, so a clipboard that went away is just left as it is
+            if let Ok(mut clipboard) = arboard::Clipboard::new() {
+                if holds(&mut clipboard, &copied) {
+                    let _ = clipboard.clear();
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// How long until the last copy is cleared, None if there is nothing waiting to be cleared
+    pub fn clears_in(&self) -> Option<Duration> {
+        self.clears_at.map(|clears_at| clears_at.saturating_duration_since(Instant::now())).filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Clear the clipboard right away if it still holds what was copied, like when the vault is locked
+    pub fn clear(&mut self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.clears_at = None;
+        let (Some(clipboard), Some(copied)) = (self.clipboard.as_mut(), self.copied.take()) else {
+            return;
+        };
+        if holds(clipboard, &copied) {
+            let _ = clipboard.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!(*fingerprint("hunter2"), *fingerprint(&String::from("hunter2")));
+        assert_ne!(*fingerprint("hunter2"), *fingerprint("hunter3"));
+        assert_ne!(*fingerprint("hunter2"), *fingerprint(""));
+    }
+
+    #[test]
+    fn test_nothing_to_clear() {
+        // Without a copy the clipboard isn't even opened, so this works without a display
+        let mut clipboard = Clipboard::new();
+        assert_eq!(clipboard.clears_in(), None);
+        clipboard.clear();
+        assert!(clipboard.clipboard.is_none());
+    }
+}
@@ -4,18 +4,95 @@ extern crate rand;
 
 use sha2::{Sha256, Digest};
 use crypto::aes::KeySize::KeySize256;
-use crypto::aes::cbc_encryptor;
 use crypto::blockmodes::PkcsPadding;
 use crypto::buffer::{BufferResult, ReadBuffer, RefReadBuffer, RefWriteBuffer, WriteBuffer};
 use rand::Rng;
 use std::str;
 use crypto::aes::cbc_decryptor;
+use argon2::{Algorithm, Argon2, Params, Version};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use zeroize::{Zeroize, Zeroizing};
+use crate::secret_memory::{SecretKey, SecretString};
+
+/// Everything that can go wrong when hashing, encrypting or decrypting
+/// Decryption errors are returned instead of panicking so one corrupted entry can't take down the whole app
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CryptoError {
+    /// The data is too short to hold a header, nonce/IV and tag
+    Truncated,
+    /// The authentication tag didn't match, the data was tampered with or the key is wrong
+    AuthenticationFailed,
+    /// A legacy CBC blob decrypted to invalid padding, which also usually means the key is wrong
+    BadPadding,
+    /// The data decrypted fine but isn't valid UTF-8
+    InvalidUtf8,
+    /// The data decrypted fine but isn't laid out the way the field should be
+    InvalidFormat,
+    /// The envelope was written with a version or algorithm this build doesn't know about
+    UnsupportedVersion,
+    /// The password is longer than the hash function can take
+    PasswordTooLong,
+    /// The stored KDF parameters are out of the range Argon2 accepts
+    InvalidKdfParams,
+}
+
+impl std::fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            CryptoError::Truncated => "encrypted data is truncated",
+            CryptoError::AuthenticationFailed => "encrypted data failed authentication",
+            CryptoError::BadPadding => "encrypted data has invalid padding",
+            CryptoError::InvalidUtf8 => "decrypted data is not valid UTF-8",
+            CryptoError::InvalidFormat => "decrypted data is not in the expected format",
+            CryptoError::UnsupportedVersion => "encrypted data uses an unsupported version",
+            CryptoError::PasswordTooLong => "password is too long",
+            CryptoError::InvalidKdfParams => "key derivation parameters are invalid",
+        };
+        write!(f, "{}", message)
+    }
+}
 
-/// Hash the master password
-pub fn hash_master(password: &str, salt: [u8; 32]) -> [u8; 32] {
+impl std::error::Error for CryptoError {}
+
+/// The envelope version and algorithm id that prefix every authenticated ciphertext
+/// A blob is laid out as [version][algorithm id][12 byte nonce][ciphertext and 16 byte tag]
+pub const ENVELOPE_VERSION: u8 = 1;
+pub const ALGORITHM_AES_256_GCM: u8 = 1;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+const ENVELOPE_OVERHEAD: usize = 2 + NONCE_LENGTH + TAG_LENGTH;
+
+/// Identifiers for the algorithm that produced a stored master hash, saved in the kdf_algorithm column
+pub const KDF_LEGACY_SHA256: i32 = 0;
+pub const KDF_ARGON2ID: i32 = 1;
+
+/// Tunable Argon2id parameters, these are stored per user so they can be raised in the future
+/// without breaking existing accounts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// The OWASP recommended minimum for Argon2id, 19 MiB of memory with 2 iterations
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// Hash the master password with a single pass of SHA-256
+/// This is only kept to verify legacy accounts before they are upgraded to Argon2id, use derive_key for anything new
+pub fn hash_master(password: &str, salt: [u8; 32]) -> Result<SecretKey, CryptoError> {
     // We check since the limit for a password and a hash is 2^64-1 bits
-    if password.as_bytes().len() > 2u64.pow(61) as usize {
-        panic!("Password is too long");
+    if password.len() > 2u64.pow(61) as usize {
+        return Err(CryptoError::PasswordTooLong);
     }
 
     // Represent the password as bytes and start a new hasher instance
@@ -24,18 +101,154 @@ pub fn hash_master(password: &str, salt: [u8; 32]) -> [u8; 32] {
 
     // Hash the password and salt
     hasher.update(converted_password);
-    hasher.update(&salt);
-    let result = hasher.finalize();
+    hasher.update(salt);
+    let mut result = hasher.finalize();
+
+    // Copy the result into locked memory and wipe the copy on the stack
+    let hashed_password = SecretKey::from_slice(&result[..]).expect("SHA-256 output is 32 bytes");
+    result.as_mut_slice().zeroize();
+
+    Ok(hashed_password)
+}
+
+/// Derive a 32 byte key from the master password using Argon2id
+/// This is used both for the stored verifier hash and for the key that encrypts the vault, with different salts
+pub fn derive_key(password: &str, salt: [u8; 32], kdf_params: KdfParams) -> Result<SecretKey, CryptoError> {
+    // Set up an Argon2id instance with the user's parameters
+    let params = Params::new(kdf_params.memory_kib, kdf_params.iterations, kdf_params.parallelism, Some(32))
+        .map_err(|_| CryptoError::InvalidKdfParams)?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+    // Derive the key straight into locked memory, the only input Argon2 can reject at this point is a password that is too long
+    let mut key = SecretKey::zeroed();
+    argon2.hash_password_into(password.as_bytes(), &salt, key.expose_mut()).map_err(|_| CryptoError::PasswordTooLong)?;
 
-    // Copy the result into a fixed-size array
-    let mut hashed_password = [0; 32];
-    hashed_password.copy_from_slice(&result[..]);
+    Ok(key)
+}
+
+/// Build the associated data that binds a ciphertext to the row and column it is stored in
+/// This means a blob copied into another entry, or swapped between the account and password columns, won't decrypt
+fn associated_data(entry_id: i32, column: &str) -> Vec<u8> {
+    [entry_id.to_le_bytes().to_vec(), column.as_bytes().to_vec()].concat()
+}
+
+/// Seal bytes into an AES-256-GCM envelope with the given associated data
+fn seal(plaintext: &[u8], key: &SecretKey, aad: &[u8]) -> Vec<u8> {
+    // Generate a random nonce
+    let nonce: [u8; NONCE_LENGTH] = rand::thread_rng().gen();
 
-    hashed_password
+    // Encrypt and authenticate the data
+    let cipher = Aes256Gcm::new(key.expose().into());
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+        .expect("Failed to encrypt data");
+
+    // Combine the header, nonce and ciphertext
+    [vec![ENVELOPE_VERSION, ALGORITHM_AES_256_GCM], nonce.to_vec(), ciphertext].concat()
 }
 
-// Encrypt a password, also used to encrypt other data like account names and website names
+/// Open an AES-256-GCM envelope, checking the header and the tag over the associated data
+/// The plaintext is returned in a buffer that is wiped when dropped
+fn open(encrypted_data: &[u8], key: &SecretKey, aad: &[u8]) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+    if encrypted_data.len() < ENVELOPE_OVERHEAD {
+        return Err(CryptoError::Truncated);
+    }
+    if encrypted_data[0] != ENVELOPE_VERSION || encrypted_data[1] != ALGORITHM_AES_256_GCM {
+        return Err(CryptoError::UnsupportedVersion);
+    }
+
+    // Split the nonce and ciphertext
+    let (nonce, ciphertext) = encrypted_data[2..].split_at(NONCE_LENGTH);
+
+    // Decrypt and check the tag
+    let cipher = Aes256Gcm::new(key.expose().into());
+    cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map(Zeroizing::new)
+        .map_err(|_| CryptoError::AuthenticationFailed)
+}
+
+/// Encrypt a field of an entry with AES-256-GCM, returning a versioned envelope
+/// The entry_id and column name are authenticated along with the ciphertext
+pub fn encrypt_field(plaintext: &str, key: &SecretKey, entry_id: i32, column: &str) -> Vec<u8> {
+    seal(plaintext.as_bytes(), key, &associated_data(entry_id, column))
+}
+
+/// Decrypt a field of an entry, which has to be an authenticated envelope for this key, row and column
+pub fn decrypt_field(encrypted_data: &[u8], key: &SecretKey, entry_id: i32, column: &str) -> Result<SecretString, CryptoError> {
+    let plaintext = open(encrypted_data, key, &associated_data(entry_id, column))?;
+
+    into_secret_string(plaintext)
+}
+
+/// Turn decrypted bytes into a SecretString, wiping them if they aren't valid UTF-8
+fn into_secret_string(mut plaintext: Zeroizing<Vec<u8>>) -> Result<SecretString, CryptoError> {
+    // Taking the Vec out leaves an empty one behind, so the bytes are only ever owned by one wiping container
+    match String::from_utf8(std::mem::take(&mut *plaintext)) {
+        Ok(text) => Ok(SecretString::from(text)),
+        Err(error) => {
+            error.into_bytes().zeroize();
+            Err(CryptoError::InvalidUtf8)
+        }
+    }
+}
+
+/// Build the associated data of one chunk of an attachment, which also binds where the chunk goes
+/// so chunks can't be reordered, swapped between attachments or cut off after any chunk but the last
+fn chunk_associated_data(attachment_id: i32, index: u32, last: bool) -> Vec<u8> {
+    [associated_data(attachment_id, "attachment_chunk"), index.to_le_bytes().to_vec(), vec![u8::from(last)]].concat()
+}
+
+/// Encrypt one chunk of an attachment with AES-256-GCM
+pub fn encrypt_chunk(chunk: &[u8], key: &SecretKey, attachment_id: i32, index: u32, last: bool) -> Vec<u8> {
+    seal(chunk, key, &chunk_associated_data(attachment_id, index, last))
+}
+
+/// Decrypt one chunk of an attachment, which has to be at the position it was encrypted for
+pub fn decrypt_chunk(encrypted_chunk: &[u8], key: &SecretKey, attachment_id: i32, index: u32, last: bool) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+    open(encrypted_chunk, key, &chunk_associated_data(attachment_id, index, last))
+}
+
+/// Generate a random 256 bit data encryption key for a new vault
+pub fn generate_data_key() -> SecretKey {
+    let mut data_key = SecretKey::zeroed();
+    rand::thread_rng().fill(data_key.expose_mut());
+    data_key
+}
+
+/// Wrap a user's data encryption key with the key derived from their master password
+/// The wrapped key is bound to the user_id so it can't be moved to another account
+pub fn wrap_key(data_key: &SecretKey, wrapping_key: &SecretKey, user_id: i32) -> Vec<u8> {
+    seal(data_key.expose(), wrapping_key, &associated_data(user_id, "wrapped_key"))
+}
+
+/// Unwrap a user's data encryption key, this fails to authenticate if the wrapping key is wrong
+pub fn unwrap_key(wrapped_key: &[u8], wrapping_key: &SecretKey, user_id: i32) -> Result<SecretKey, CryptoError> {
+    let data_key = open(wrapped_key, wrapping_key, &associated_data(user_id, "wrapped_key"))?;
+
+    SecretKey::from_slice(&data_key).ok_or(CryptoError::Truncated)
+}
+
+/// Decrypt a field of an account that hasn't been migrated off legacy AES-256-CBC yet
+/// Returns the plaintext and whether it was a CBC blob, which then has to be reencrypted into an envelope
+/// Only blobs without our envelope header are tried as CBC, anything with it is authenticated or rejected,
+/// so a tampered envelope can't come back as plaintext that happens to have valid padding
+pub fn decrypt_legacy_field(encrypted_data: &[u8], key: &SecretKey, entry_id: i32, column: &str) -> Result<(SecretString, bool), CryptoError> {
+    match decrypt_field(encrypted_data, key, entry_id, column) {
+        Ok(plaintext) => Ok((plaintext, false)),
+        // A CBC blob is a 16 byte IV followed by whole blocks
+        Err(CryptoError::UnsupportedVersion) if encrypted_data.len() >= 32 && encrypted_data.len().is_multiple_of(16) => {
+            decrypt_password(encrypted_data, key.expose()).map(|plaintext| (plaintext, true))
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// Encrypt a password with AES-256-CBC
+/// This is the legacy format without authentication, new data is never written with it
+/// It is only kept so the tests can produce blobs like the ones found in old databases
+#[cfg(test)]
 pub fn encrypt_password(password: &str, key: &[u8; 32]) -> Vec<u8> {
+    use crypto::aes::cbc_encryptor;
+
     // Generate a random IV
     let iv: [u8; 16] = rand::thread_rng().gen();
 
@@ -63,8 +276,13 @@ pub fn encrypt_password(password: &str, key: &[u8; 32]) -> Vec<u8> {
     [iv.to_vec(), encrypted_data].concat()
 }
 
-/// Decrypt a password or other data
-pub fn decrypt_password(encrypted_data: &[u8], key: &[u8]) -> String {
+/// Decrypt a legacy AES-256-CBC password or other data
+pub fn decrypt_password(encrypted_data: &[u8], key: &[u8]) -> Result<SecretString, CryptoError> {
+    // Make sure there is an IV and at least one block
+    if encrypted_data.len() < 32 {
+        return Err(CryptoError::Truncated);
+    }
+
     // Split the IV and ciphertext
     let (iv, ciphertext) = encrypted_data.split_at(16);
 
@@ -76,38 +294,51 @@ pub fn decrypt_password(encrypted_data: &[u8], key: &[u8]) -> String {
     let mut buffer = [0; 4096];
     let mut write_buffer = RefWriteBuffer::new(&mut buffer);
 
-    // Perform decryption
-    let mut decrypted_data = Vec::new();
-    loop {
-        let result = decryptor.decrypt(&mut read_buffer, &mut write_buffer, true).unwrap();
+    // Perform decryption, reserving the whole length up front so the plaintext is never reallocated
+    let mut decrypted_data = Zeroizing::new(Vec::with_capacity(ciphertext.len()));
+    let outcome = loop {
+        let result = match decryptor.decrypt(&mut read_buffer, &mut write_buffer, true) {
+            Ok(result) => result,
+            Err(_) => break Err(CryptoError::BadPadding),
+        };
         decrypted_data.extend(write_buffer.take_read_buffer().take_remaining().iter().copied());
 
         match result {
-            BufferResult::BufferUnderflow => break,
+            BufferResult::BufferUnderflow => break Ok(()),
             BufferResult::BufferOverflow => {}
         }
-    }
+    };
+
+    // Wipe the working buffer whether or not decryption worked
+    buffer.zeroize();
+    outcome?;
 
     // Convert the decrypted data to a string
-    String::from_utf8(decrypted_data).unwrap()
+    into_secret_string(decrypted_data)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    /// Build a key filled with a single byte
+    fn test_key(byte: u8) -> SecretKey {
+        SecretKey::from_slice(&[byte; 32]).unwrap()
+    }
+
     #[test]
     fn test_hash_master() {
         let password = "supersecret";
         let salt = [0u8; 32]; // Using a zeroed salt for simplicity
 
         // Hash the master password
-        let hashed_password = hash_master(password, salt);
+        let hashed_password = hash_master(password, salt).unwrap();
 
         // Ensure the hashed password is 32 bytes long
-        assert_eq!(hashed_password.len(), 32);
+        assert_eq!(hashed_password.expose().len(), 32);
 
         // Hash the same password with the same salt again
-        let hashed_password_again = hash_master(password, salt);
+        let hashed_password_again = hash_master(password, salt).unwrap();
 
         // Ensure the hashed passwords are the same
         assert_eq!(hashed_password, hashed_password_again);
@@ -120,10 +351,10 @@ mod tests {
         let salt2 = [1u8; 32]; // Using a different salt
 
         // Hash the master password with the first salt
-        let hashed_password1 = hash_master(password, salt1);
+        let hashed_password1 = hash_master(password, salt1).unwrap();
 
         // Hash the master password with the second salt
-        let hashed_password2 = hash_master(password, salt2);
+        let hashed_password2 = hash_master(password, salt2).unwrap();
 
         // Ensure the hashed passwords are different
         assert_ne!(hashed_password1, hashed_password2);
@@ -136,31 +367,206 @@ mod tests {
         let salt = [0u8; 32]; // Using a zeroed salt for simplicity
 
         // Hash the first password
-        let hashed_password1 = hash_master(password1, salt);
+        let hashed_password1 = hash_master(password1, salt).unwrap();
 
         // Hash the second password
-        let hashed_password2 = hash_master(password2, salt);
+        let hashed_password2 = hash_master(password2, salt).unwrap();
 
         // Ensure the hashed passwords are different
         assert_ne!(hashed_password1, hashed_password2);
     }
 
+    // Small parameters so the tests don't take too long in debug builds
+    const TEST_PARAMS: KdfParams = KdfParams { memory_kib: 1024, iterations: 1, parallelism: 1 };
+
+    #[test]
+    fn test_derive_key() {
+        let password = "supersecret";
+        let salt = [0u8; 32];
+
+        // Deriving twice with the same inputs should give the same key
+        let key = derive_key(password, salt, TEST_PARAMS).unwrap();
+        let key_again = derive_key(password, salt, TEST_PARAMS).unwrap();
+        assert_eq!(key, key_again);
+
+        // The Argon2id key should not be the same as the legacy SHA-256 hash
+        assert_ne!(key, hash_master(password, salt).unwrap());
+    }
+
+    #[test]
+    fn test_derive_key_with_different_inputs() {
+        let password = "supersecret";
+        let salt = [0u8; 32];
+        let key = derive_key(password, salt, TEST_PARAMS).unwrap();
+
+        // A different salt, password, or set of parameters should all change the key
+        assert_ne!(key, derive_key(password, [1u8; 32], TEST_PARAMS).unwrap());
+        assert_ne!(key, derive_key("anothersecret", salt, TEST_PARAMS).unwrap());
+        let stronger_params = KdfParams { iterations: 2, ..TEST_PARAMS };
+        assert_ne!(key, derive_key(password, salt, stronger_params).unwrap());
+    }
+
+    #[test]
+    fn test_derive_key_with_invalid_params() {
+        // Argon2 needs at least 8 KiB of memory per lane
+        let params = KdfParams { memory_kib: 1, iterations: 1, parallelism: 1 };
+        assert_eq!(derive_key("supersecret", [0u8; 32], params), Err(CryptoError::InvalidKdfParams));
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_field() {
+        let password = "supersecret";
+        let key = test_key(0); // Using a zeroed key for simplicity
+
+        // Encrypt the password and check the envelope header
+        let encrypted_password = encrypt_field(password, &key, 1, "password");
+        assert_eq!(encrypted_password[0], ENVELOPE_VERSION);
+        assert_eq!(encrypted_password[1], ALGORITHM_AES_256_GCM);
+        assert_eq!(encrypted_password.len(), password.len() + ENVELOPE_OVERHEAD);
+
+        // Decrypt the password
+        let decrypted_password = decrypt_field(&encrypted_password, &key, 1, "password").unwrap();
+        assert_eq!(password, decrypted_password.expose());
+        assert!(!decrypt_legacy_field(&encrypted_password, &key, 1, "password").unwrap().1);
+    }
+
+    #[test]
+    fn test_field_bound_to_entry_and_column() {
+        let key = test_key(0);
+        let encrypted_password = encrypt_field("supersecret", &key, 1, "password");
+
+        // The blob shouldn't open when moved to another entry or another column
+        assert_eq!(decrypt_field(&encrypted_password, &key, 2, "password"), Err(CryptoError::AuthenticationFailed));
+        assert_eq!(decrypt_field(&encrypted_password, &key, 1, "account"), Err(CryptoError::AuthenticationFailed));
+    }
+
+    #[test]
+    fn test_chunk_bound_to_position() {
+        let key = test_key(0);
+        let encrypted_chunk = encrypt_chunk(b"recovery codes", &key, 1, 0, false);
+        assert_eq!(*decrypt_chunk(&encrypted_chunk, &key, 1, 0, false).unwrap(), b"recovery codes");
+
+        // Moving the chunk to another attachment or position, or passing it off as the last one, is caught
+        assert_eq!(decrypt_chunk(&encrypted_chunk, &key, 2, 0, false), Err(CryptoError::AuthenticationFailed));
+        assert_eq!(decrypt_chunk(&encrypted_chunk, &key, 1, 1, false), Err(CryptoError::AuthenticationFailed));
+        assert_eq!(decrypt_chunk(&encrypted_chunk, &key, 1, 0, true), Err(CryptoError::AuthenticationFailed));
+    }
+
+    #[test]
+    fn test_tampered_field_is_rejected() {
+        let key = test_key(0);
+        let mut encrypted_password = encrypt_field("supersecret", &key, 1, "password");
+
+        // Flip a bit in the ciphertext
+        let last = encrypted_password.len() - 1;
+        encrypted_password[last] ^= 1;
+        assert_eq!(decrypt_field(&encrypted_password, &key, 1, "password"), Err(CryptoError::AuthenticationFailed));
+
+        // A wrong key should also fail to authenticate
+        let encrypted_password = encrypt_field("supersecret", &key, 1, "password");
+        assert_eq!(decrypt_field(&encrypted_password, &test_key(1), 1, "password"), Err(CryptoError::AuthenticationFailed));
+    }
+
+    #[test]
+    fn test_malformed_fields_are_errors() {
+        let key = test_key(0);
+        let encrypted_password = encrypt_field("supersecret", &key, 1, "password");
+
+        // Cutting the envelope short
+        assert_eq!(decrypt_field(&encrypted_password[..10], &key, 1, "password"), Err(CryptoError::Truncated));
+        assert_eq!(decrypt_field(&[], &key, 1, "password"), Err(CryptoError::Truncated));
+
+        // An envelope from a future version
+        let mut future_password = encrypted_password.clone();
+        future_password[0] = ENVELOPE_VERSION + 1;
+        assert_eq!(decrypt_field(&future_password, &key, 1, "password"), Err(CryptoError::UnsupportedVersion));
+
+        // Plaintext that isn't UTF-8
+        let nonce = [0u8; NONCE_LENGTH];
+        let aad = associated_data(1, "password");
+        let ciphertext = Aes256Gcm::new(key.expose().into())
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: &[0xff, 0xfe], aad: &aad })
+            .unwrap();
+        let invalid_utf8 = [vec![ENVELOPE_VERSION, ALGORITHM_AES_256_GCM], nonce.to_vec(), ciphertext].concat();
+        assert_eq!(decrypt_field(&invalid_utf8, &key, 1, "password"), Err(CryptoError::InvalidUtf8));
+    }
+
+    #[test]
+    fn test_legacy_cbc_with_wrong_key_is_an_error() {
+        let legacy_password = encrypt_password("supersecret", test_key(0).expose());
+
+        // A wrong key almost always breaks the padding, and if it doesn't the plaintext is garbage
+        assert!(decrypt_legacy_field(&legacy_password, &test_key(1), 1, "password").is_err());
+        assert_eq!(decrypt_password(&legacy_password[..16], test_key(0).expose()), Err(CryptoError::Truncated));
+    }
+
+    #[test]
+    fn test_legacy_cbc_still_decrypts() {
+        let password = "supersecret";
+        let key = test_key(0);
+
+        // Blobs written before the migration are still readable while migrating, and flagged for reencryption
+        let legacy_password = encrypt_password(password, key.expose());
+        let (plaintext, legacy) = decrypt_legacy_field(&legacy_password, &key, 1, "password").unwrap();
+        assert_eq!((plaintext.expose(), legacy), (password, true));
+
+        // Outside of the migration they are never read
+        assert_eq!(decrypt_field(&legacy_password, &key, 1, "password"), Err(CryptoError::UnsupportedVersion));
+    }
+
+    #[test]
+    fn test_envelope_header_is_never_read_as_cbc() {
+        let key = test_key(0);
+
+        // A CBC blob that starts with our header decrypts fine as CBC, but is treated as the envelope it claims to be
+        let mut legacy_password = encrypt_password("supersecret", key.expose());
+        while legacy_password[..2] != [ENVELOPE_VERSION, ALGORITHM_AES_256_GCM] {
+            legacy_password = encrypt_password("supersecret", key.expose());
+        }
+        assert!(decrypt_password(&legacy_password, key.expose()).is_ok());
+        assert_eq!(decrypt_legacy_field(&legacy_password, &key, 1, "password"), Err(CryptoError::AuthenticationFailed));
+
+        // The same goes for a tampered envelope that happens to be a multiple of the block size
+        let mut encrypted_password = encrypt_field("0123456789abcdef01", &key, 1, "password");
+        assert_eq!(encrypted_password.len() % 16, 0);
+        encrypted_password[20] ^= 1;
+        assert_eq!(decrypt_legacy_field(&encrypted_password, &key, 1, "password"), Err(CryptoError::AuthenticationFailed));
+    }
+
+    #[test]
+    fn test_wrap_unwrap_key() {
+        let data_key = generate_data_key();
+        let wrapping_key = test_key(0);
+
+        // The data key comes back out with the right wrapping key and user
+        let wrapped_key = wrap_key(&data_key, &wrapping_key, 1);
+        assert_eq!(unwrap_key(&wrapped_key, &wrapping_key, 1).as_ref(), Ok(&data_key));
+
+        // A different master password or a different user can't unwrap it
+        assert_eq!(unwrap_key(&wrapped_key, &test_key(1), 1), Err(CryptoError::AuthenticationFailed));
+        assert_eq!(unwrap_key(&wrapped_key, &wrapping_key, 2), Err(CryptoError::AuthenticationFailed));
+
+        // Rewrapping under a new key keeps the same data key
+        let rewrapped_key = wrap_key(&data_key, &test_key(1), 1);
+        assert_eq!(unwrap_key(&rewrapped_key, &test_key(1), 1).as_ref(), Ok(&data_key));
+    }
+
     #[test]
     fn test_encrypt_decrypt_password() {
         let password = "supersecret";
-        let key = [0u8; 32]; // Using a zeroed key for simplicity
+        let key = test_key(0); // Using a zeroed key for simplicity
 
         // Encrypt the password
-        let encrypted_password = encrypt_password(password, &key);
+        let encrypted_password = encrypt_password(password, key.expose());
 
         // Ensure the encrypted password is not the same as the original password
         assert_ne!(password.as_bytes(), &encrypted_password[..]);
         
         // Decrypt the password
-        let decrypted_password = decrypt_password(&encrypted_password, &key);
+        let decrypted_password = decrypt_password(&encrypted_password, key.expose()).unwrap();
 
         // Ensure the decrypted password is the same as the original password
-        assert_eq!(password, decrypted_password);
+        assert_eq!(password, decrypted_password.expose());
     }
 
     #[test]
@@ -169,15 +575,15 @@ mod tests {
         let key = "fhwebsdkfuyr1[23r4ibth34--";
         let salt = [0u8; 32]; // Using a zeroed salt for simplicity
 
-        let hashed_key = hash_master(key, salt);
+        let hashed_key = hash_master(key, salt).unwrap();
         
         // Encrypt the password
-        let encrypted_password = encrypt_password(password, &hashed_key);
+        let encrypted_password = encrypt_password(password, hashed_key.expose());
 
         // Decrypt the password
-        let decrypted_password = decrypt_password(&encrypted_password, &hashed_key);
+        let decrypted_password = decrypt_password(&encrypted_password, hashed_key.expose()).unwrap();
 
         // Ensure the decrypted password is the same as the original password
-        assert_eq!(password, decrypted_password);
+        assert_eq!(password, decrypted_password.expose());
     }
 }
\ No newline at end of file
@@ -0,0 +1,439 @@
+use crate::encryption_algorithms::CryptoError;
+use crate::otp::OtpParams;
+use crate::secret_memory::SecretString;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+/// A user defined key/value pair on an entry, hidden ones are treated like passwords in the GUI
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CustomField {
+    pub name: SecretString,
+    pub value: SecretString,
+    pub hidden: bool,
+}
+
+/// The parts of an entry beyond its account, website and password
+/// They are encrypted together as one JSON document, so new ones can be added without another column each
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EntryDetails {
+    /// The name the entry is listed under, separate from the username it logs in with
+    pub title: SecretString,
+    pub notes: SecretString,
+    /// Addresses beyond the main website, for example a separate login page
+    pub urls: Vec<SecretString>,
+    pub tags: Vec<SecretString>,
+    pub custom_fields: Vec<CustomField>,
+    /// The two-factor secret of the account, if its codes are kept here instead of in a separate app
+    pub otp: Option<OtpParams>,
+}
+
+/// What sort of thing an entry holds, stored unencrypted in the kind column so it is known without decrypting the payload
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    #[default]
+    Login,
+    SecureNote,
+    CreditCard,
+    Identity,
+    SshKey,
+    WiFi,
+    ApiToken,
+}
+
+impl EntryKind {
+    pub const ALL: [EntryKind; 7] = [
+        EntryKind::Login,
+        EntryKind::SecureNote,
+        EntryKind::CreditCard,
+        EntryKind::Identity,
+        EntryKind::SshKey,
+        EntryKind::WiFi,
+        EntryKind::ApiToken,
+    ];
+
+    /// The number stored in the kind column, these must never change once released
+    pub fn code(self) -> i32 {
+        match self {
+            EntryKind::Login => 0,
+            EntryKind::SecureNote => 1,
+            EntryKind::CreditCard => 2,
+            EntryKind::Identity => 3,
+            EntryKind::SshKey => 4,
+            EntryKind::WiFi => 5,
+            EntryKind::ApiToken => 6,
+        }
+    }
+
+    /// Get the kind stored in the kind column, None if it was written by a newer version
+    pub fn from_code(code: i32) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.code() == code)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EntryKind::Login => "Login",
+            EntryKind::SecureNote => "Secure note",
+            EntryKind::CreditCard => "Credit card",
+            EntryKind::Identity => "Identity",
+            EntryKind::SshKey => "SSH key",
+            EntryKind::WiFi => "Wi-Fi",
+            EntryKind::ApiToken => "API token",
+        }
+    }
+
+    /// What the password field of an entry holds for this kind, every kind keeps its main secret there
+    /// so copying, the password history and the strength check work the same for all of them
+    pub fn password_label(self) -> &'static str {
+        match self {
+            EntryKind::Login | EntryKind::Identity => "Password",
+            EntryKind::SecureNote => "Secret",
+            EntryKind::CreditCard => "PIN",
+            EntryKind::SshKey | EntryKind::WiFi => "Passphrase",
+            EntryKind::ApiToken => "Token",
+        }
+    }
+
+    /// Whether the entry signs in somewhere, so it has an account and website of its own
+    pub fn has_account(self) -> bool {
+        matches!(self, EntryKind::Login | EntryKind::ApiToken)
+    }
+
+    /// Whether the user picks the secret, so it is worth generating one and rating how safe it is
+    pub fn has_chosen_password(self) -> bool {
+        matches!(self, EntryKind::Login | EntryKind::Identity | EntryKind::WiFi)
+    }
+}
+
+/// The security of a Wi-Fi network
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WifiSecurity {
+    Open,
+    Wep,
+    #[default]
+    WpaPersonal,
+    Wpa3Personal,
+    Enterprise,
+}
+
+impl WifiSecurity {
+    pub const ALL: [WifiSecurity; 5] = [
+        WifiSecurity::Open,
+        WifiSecurity::Wep,
+        WifiSecurity::WpaPersonal,
+        WifiSecurity::Wpa3Personal,
+        WifiSecurity::Enterprise,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WifiSecurity::Open => "Open",
+            WifiSecurity::Wep => "WEP",
+            WifiSecurity::WpaPersonal => "WPA/WPA2 Personal",
+            WifiSecurity::Wpa3Personal => "WPA3 Personal",
+            WifiSecurity::Enterprise => "Enterprise",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CardFields {
+    pub cardholder: SecretString,
+    pub number: SecretString,
+    /// As printed on the card, MM/YY
+    pub expiry: SecretString,
+    pub cvv: SecretString,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IdentityFields {
+    pub full_name: SecretString,
+    pub email: SecretString,
+    pub phone: SecretString,
+    pub address: SecretString,
+    pub city: SecretString,
+    pub region: SecretString,
+    pub postal_code: SecretString,
+    pub country: SecretString,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SshKeyFields {
+    pub private_key: SecretString,
+    pub public_key: SecretString,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WifiFields {
+    pub ssid: SecretString,
+    pub security: WifiSecurity,
+    pub hidden_network: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiTokenFields {
+    /// The public half of the credential, like an access key id or client id
+    pub key_id: SecretString,
+    /// When the token stops working, as typed by the user
+    pub expires: SecretString,
+}
+
+/// The fields only some kinds of entry have, encrypted in the payload column
+/// Logins and secure notes get by with the account, website, password and notes every entry has
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Payload {
+    #[default]
+    Login,
+    SecureNote,
+    CreditCard(CardFields),
+    Identity(IdentityFields),
+    SshKey(SshKeyFields),
+    WiFi(WifiFields),
+    ApiToken(ApiTokenFields),
+}
+
+impl Payload {
+    /// An empty payload for a kind, used when the kind is picked on the add screen
+    pub fn new(kind: EntryKind) -> Self {
+        match kind {
+            EntryKind::Login => Payload::Login,
+            EntryKind::SecureNote => Payload::SecureNote,
+            EntryKind::CreditCard => Payload::CreditCard(CardFields::default()),
+            EntryKind::Identity => Payload::Identity(IdentityFields::default()),
+            EntryKind::SshKey => Payload::SshKey(SshKeyFields::default()),
+            EntryKind::WiFi => Payload::WiFi(WifiFields::default()),
+            EntryKind::ApiToken => Payload::ApiToken(ApiTokenFields::default()),
+        }
+    }
+
+    pub fn kind(&self) -> EntryKind {
+        match self {
+            Payload::Login => EntryKind::Login,
+            Payload::SecureNote => EntryKind::SecureNote,
+            Payload::CreditCard(_) => EntryKind::CreditCard,
+            Payload::Identity(_) => EntryKind::Identity,
+            Payload::SshKey(_) => EntryKind::SshKey,
+            Payload::WiFi(_) => EntryKind::WiFi,
+            Payload::ApiToken(_) => EntryKind::ApiToken,
+        }
+    }
+
+    /// The fields of the payload that search looks at, secrets like card numbers and private keys are left out
+    pub fn search_fields(&self) -> Vec<&SecretString> {
+        match self {
+            Payload::Login | Payload::SecureNote | Payload::SshKey(_) => Vec::new(),
+            Payload::CreditCard(card) => vec![&card.cardholder],
+            Payload::Identity(identity) => vec![&identity.full_name, &identity.email, &identity.phone, &identity.city],
+            Payload::WiFi(wifi) => vec![&wifi.ssid],
+            Payload::ApiToken(token) => vec![&token.key_id],
+        }
+    }
+
+    /// A name for entries without a title or website, like the network name of a Wi-Fi entry
+    pub fn label(&self) -> Option<&str> {
+        let label = match self {
+            Payload::CreditCard(card) => &card.cardholder,
+            Payload::Identity(identity) => &identity.full_name,
+            Payload::WiFi(wifi) => &wifi.ssid,
+            Payload::ApiToken(token) => &token.key_id,
+            Payload::Login | Payload::SecureNote | Payload::SshKey(_) => return None,
+        };
+        Some(label.expose()).filter(|label| !label.is_empty())
+    }
+
+    /// Check the payload before it is saved, returning what is wrong with it
+    pub fn validate(&self) -> Result<(), String> {
+        if let Payload::CreditCard(card) = self {
+            if !card.number.expose().is_empty() && !luhn_valid(card.number.expose()) {
+                return Err("The card number is not valid, please check it for typos".to_string());
+            }
+            if !card.expiry.expose().is_empty() && !expiry_valid(card.expiry.expose()) {
+                return Err("The expiry date should be written as MM/YY".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check a card number with the Luhn checksum, spaces and dashes between digit groups are allowed
+pub fn luhn_valid(number: &str) -> bool {
+    let digits: Zeroizing<Vec<u32>> = Zeroizing::new(number.chars().filter(|c| *c != ' ' && *c != '-').map(|c| c.to_digit(10).unwrap_or(u32::MAX)).collect());
+    if digits.len() < 12 || digits.len() > 19 || digits.contains(&u32::MAX) {
+        return false;
+    }
+
+    // Double every second digit from the right, subtracting 9 when that makes it two digits
+    let sum: u32 = digits.iter().rev().enumerate()
+        .map(|(index, &digit)| match (index % 2, digit * 2) {
+            (1, doubled) if doubled > 9 => doubled - 9,
+            (1, doubled) => doubled,
+            _ => digit,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// Check an expiry date written as MM/YY
+fn expiry_valid(expiry: &str) -> bool {
+    match expiry.split_once('/') {
+        Some((month, year)) => {
+            month.len() == 2 && year.len() == 2 && year.chars().all(|c| c.is_ascii_digit())
+                && month.parse::<u32>().is_ok_and(|month| (1..=12).contains(&month))
+        }
+        None => false,
+    }
+}
+
+/// Everything about an entry that the user can change, used when adding and editing entries
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EntryData {
+    pub account: SecretString,
+    pub website: SecretString,
+    pub password: SecretString,
+    pub details: EntryDetails,
+    pub payload: Payload,
+    pub favorite: bool,
+    /// The folder the entry is filed in, None if it isn't in one
+    pub folder_id: Option<i32>,
+}
+
+impl EntryData {
+    /// An entry with just an account, password and website, like the ones older versions stored
+    pub fn new(account: &str, password: &str, website: &str) -> Self {
+        Self {
+            account: SecretString::from(account),
+            website: SecretString::from(website),
+            password: SecretString::from(password),
+            ..Self::default()
+        }
+    }
+}
+
+/// A decrypted entry of a vault, identified by its row in the database
+/// Each encrypted part is a Result so a corrupted entry can be shown as such without hiding the rest of the vault
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub id: i32,
+    pub account: Result<SecretString, CryptoError>,
+    pub website: Result<SecretString, CryptoError>,
+    pub password: Result<SecretString, CryptoError>,
+    pub details: Result<EntryDetails, CryptoError>,
+    pub kind: EntryKind,
+    pub payload: Result<Payload, CryptoError>,
+    pub favorite: bool,
+    /// Timestamps in seconds since the unix epoch, entries from before they were recorded don't have them
+    pub created_at: Option<i64>,
+    pub modified_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+    pub folder_id: Option<i32>,
+}
+
+impl Entry {
+    /// Copy the editable parts of the entry out, fails if any of them couldn't be decrypted
+    pub fn data(&self) -> Result<EntryData, CryptoError> {
+        Ok(EntryData {
+            account: self.account.clone()?,
+            website: self.website.clone()?,
+            password: self.password.clone()?,
+            details: self.details.clone()?,
+            payload: self.payload.clone()?,
+            favorite: self.favorite,
+            folder_id: self.folder_id,
+        })
+    }
+
+    /// The name to list the entry under, the title if it has one, otherwise the website or something from the payload
+    pub fn display_name(&self) -> Option<&str> {
+        if let Ok(details) = &self.details {
+            if !details.title.expose().is_empty() {
+                return Some(details.title.expose());
+            }
+        }
+        match (&self.website, &self.payload) {
+            (Ok(website), _) if !website.expose().is_empty() => Some(website.expose()),
+            (Ok(website), Ok(payload)) => Some(payload.label().unwrap_or(website.expose())),
+            _ => None,
+        }
+    }
+}
+
+/// Split a comma separated list of tags as typed in the GUI, dropping empty ones
+pub fn parse_tags(text: &str) -> Vec<SecretString> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(SecretString::from)
+        .collect()
+}
+
+/// Join tags back into the comma separated form they are edited in
+pub fn join_tags(tags: &[SecretString]) -> SecretString {
+    let mut text = SecretString::new();
+    for (index, tag) in tags.iter().enumerate() {
+        if index > 0 {
+            text.push_str(", ");
+        }
+        text.push_str(tag.expose());
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_details_round_trip() {
+        let details = EntryDetails {
+            title: SecretString::from("Work email"),
+            notes: SecretString::from("Recovery codes are in the safe"),
+            urls: vec![SecretString::from("https://mail.example.com")],
+            tags: parse_tags(" work, , email "),
+            custom_fields: vec![CustomField { name: SecretString::from("PIN"), value: SecretString::from("1234"), hidden: true }],
+            otp: None,
+        };
+        assert_eq!(join_tags(&details.tags).expose(), "work, email");
+
+        let json = serde_json::to_string(&details).unwrap();
+        assert_eq!(serde_json::from_str::<EntryDetails>(&json).unwrap(), details);
+
+        // Missing parts fall back to their defaults, so older documents still load
+        assert_eq!(serde_json::from_str::<EntryDetails>("{}").unwrap(), EntryDetails::default());
+    }
+
+    #[test]
+    fn test_kind_codes() {
+        for kind in EntryKind::ALL {
+            assert_eq!(EntryKind::from_code(kind.code()), Some(kind));
+            assert_eq!(Payload::new(kind).kind(), kind);
+        }
+        assert_eq!(EntryKind::from_code(100), None);
+    }
+
+    #[test]
+    fn test_card_validation() {
+        // Test numbers published by the card networks
+        assert!(luhn_valid("4111 1111 1111 1111"));
+        assert!(luhn_valid("5555-5555-5555-4444"));
+        assert!(luhn_valid("378282246310005"));
+        assert!(!luhn_valid("4111 1111 1111 1112"));
+        assert!(!luhn_valid("4111"));
+        assert!(!luhn_valid("4111 1111 1111 111a"));
+
+        let mut card = CardFields { number: SecretString::from("4111111111111111"), expiry: SecretString::from("09/29"), ..CardFields::default() };
+        assert!(Payload::CreditCard(card.clone()).validate().is_ok());
+        card.expiry = SecretString::from("13/29");
+        assert!(Payload::CreditCard(card.clone()).validate().is_err());
+        card.expiry = SecretString::from("09/29");
+        card.number = SecretString::from("4111111111111112");
+        assert!(Payload::CreditCard(card).validate().is_err());
+
+        // Other kinds have nothing to check
+        assert!(Payload::new(EntryKind::WiFi).validate().is_ok());
+    }
+}
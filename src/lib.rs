@@ -0,0 +1,8 @@
+//! The vault itself, shared by the egui app and the cryptkeeper command line tool
+pub mod encryption_algorithms;
+pub mod entry;
+pub mod otp;
+pub mod password_generator;
+pub mod secret_memory;
+pub mod storage_options_sql;
+pub mod vault_cache;
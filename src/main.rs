@@ -1,117 +1,369 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
-mod storage_options_sql;
-mod encryption_algorithms;
-mod password_generator;
+mod clipboard;
+use password_manager::{entry, password_generator, storage_options_sql};
 use egui::Color32;
 use egui::RichText;
 use egui::Stroke;
-use rusqlite::Connection;
 use eframe::egui;
-use clipboard::{ClipboardContext, ClipboardProvider};
+use clipboard::Clipboard;
 use egui::FontFamily::Proportional;
 use egui::FontId;
 use egui::TextStyle::*;
-use rand::Rng;
+use entry::{CustomField, EntryData, EntryKind, Payload, WifiSecurity};
+use password_manager::otp::{OtpCounter, OtpParams};
+use password_manager::secret_memory::SecretString;
+use storage_options_sql::{Attachment, AttachmentMetadata, AutoLock, Folder, PasswordHistory, Vault};
+use std::collections::HashMap;
+use std::sync::mpsc::Receiver;
+use password_manager::vault_cache::{VaultCache, VaultChange};
 
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum Screen {
     Login,
-    UserNotFound,
     EnterNewMaster,
     InsertMaster,
     Main,
     AddPassword,
+    EditPassword,
     GetPassword,
+    Trash,
+    Settings,
     ChangeMasterPassword
 }
+/// How long the main screen offers to undo a delete
+const UNDO_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// What was clicked on an entry in the main screen
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum EntryAction {
+    Favorite,
+    Get,
+    Change,
+    Delete
+}
+
+/// What was clicked in the attachments of the get password screen
+enum AttachmentAction {
+    Attach(std::path::PathBuf),
+    Export(i32),
+    ConfirmRemove(i32),
+    CancelRemove,
+    Remove(i32),
+}
+
+/// What is being dragged onto a folder in the main screen
+#[derive(Clone, Copy)]
+enum Dragged {
+    Entry(i32),
+    Folder(i32),
+}
+
+/// What was done in the folder sidebar, applied once the sidebar has been drawn
+enum FolderAction {
+    Select(Option<i32>),
+    MoveEntry(i32, Option<i32>),
+    MoveFolder(i32, Option<i32>),
+    StartRename(i32),
+    Rename,
+    CancelRename,
+    Add,
+    Remove(i32),
+}
 struct PasswordManagerApp{
+    vault: Vault,
+    vault_path: String,
     current_screen: Screen,
     display_incorrect_msg: bool,
-    user_id: i32,
     account: String,
-    salt: [u8; 32],
-    kdf_salt: [u8; 32],
-    hashed_master: [u8; 32],
-    text_buffer: String,
-    current_account: String,
-    current_website: String,
-    current_password: String,
-    password_attempts: i32,
-    password_limit: bool,
-    master_safe: (u32, Vec<String>)
+    unlocked: Option<VaultCache>,
+    vault_changes: Option<Receiver<VaultChange>>,
+    text_buffer: SecretString,
+    search_query: SecretString,
+    search_results: Vec<usize>,
+    selected_folder: Option<i32>,
+    folder_counts: HashMap<i32, usize>,
+    folder_name: SecretString,
+    renaming_folder: Option<i32>,
+    selected_entry: Option<i32>,
+    password_history: Vec<PasswordHistory>,
+    attachments: Vec<Attachment>,
+    attachment_path: String,
+    /// Where the last attachment was exported to
+    attachment_message: String,
+    confirm_remove_attachment: Option<i32>,
+    undo_trash: Option<(i32, std::time::Instant)>,
+    trash_retention_days: u32,
+    clipboard: Clipboard,
+    /// How many seconds copies stay in the clipboard, read once at unlock
+    clipboard_timeout: u32,
+    current_entry: EntryData,
+    current_tags: SecretString,
+    /// The two-factor secret of the entry being edited, as an otpauth:// URI
+    current_otp: SecretString,
+    confirm_buffer: SecretString,
+    locked_until: Option<std::time::Instant>,
+    master_safe: (u32, Vec<String>),
+    /// The score new master passwords need in the open vault
+    min_master_score: u32,
+    /// The minimum strength being picked on the settings screen, it only applies once it is saved
+    settings_min_master_score: u32,
+    /// The new master password the strength meter was last worked out for, so it isn't rated again on every repaint
+    rated_master: SecretString,
+    master_rating: (u32, Vec<String>),
+    /// When the open vault locks itself
+    auto_lock: AutoLock,
+    /// The auto-lock being picked on the settings screen, it only applies once it is saved
+    settings_auto_lock: AutoLock,
+    last_activity: std::time::Instant,
+    /// How many seconds revealed passwords stay shown in the open vault
+    reveal_timeout: u32,
+    /// The reveal timeout being picked on the settings screen, it only applies once it is saved
+    settings_reveal_timeout: u32,
+    /// When the password was opened in big characters for reading, it closes again like a revealed field
+    reading: Option<std::time::Instant>,
+    /// Why the vault was locked without the user asking, shown when they are asked for their master password again
+    lock_message: String,
+    error_message: String
 }
 
 impl PasswordManagerApp {
-    /// Creates a new PasswordManagerApp by initializing the fields to their default values
-    fn new() -> Self {
+    /// Creates a new PasswordManagerApp on the given vault by initializing the fields to their default values
+    fn new(vault: Vault) -> Self {
         Self {
+            min_master_score: vault.min_master_score(),
+            settings_min_master_score: 0,
+            auto_lock: vault.auto_lock(),
+            settings_auto_lock: AutoLock::default(),
+            reveal_timeout: vault.reveal_timeout(),
+            settings_reveal_timeout: 0,
+            reading: None,
+            last_activity: std::time::Instant::now(),
+            lock_message: String::new(),
+            vault_path: vault.path().display().to_string(),
+            vault,
             current_screen: Screen::Login,
             display_incorrect_msg: false,
-            user_id: 0,
             account: String::new(),
-            salt: [0; 32],
-            kdf_salt: [0; 32],
-            hashed_master: [0; 32],
-            text_buffer: String::new(),
-            current_account: String::new(),
-            current_website: String::new(),
-            current_password: String::new(),
-            password_attempts: 0,
-            password_limit: false,
-            master_safe: (0, Vec::new())
+            unlocked: None,
+            vault_changes: None,
+            text_buffer: SecretString::new(),
+            search_query: SecretString::new(),
+            search_results: Vec::new(),
+            selected_folder: None,
+            folder_counts: HashMap::new(),
+            folder_name: SecretString::new(),
+            renaming_folder: None,
+            selected_entry: None,
+            password_history: Vec::new(),
+            attachments: Vec::new(),
+            attachment_path: String::new(),
+            attachment_message: String::new(),
+            confirm_remove_attachment: None,
+            undo_trash: None,
+            trash_retention_days: 0,
+            clipboard: Clipboard::new(),
+            clipboard_timeout: 0,
+            current_entry: EntryData::default(),
+            current_tags: SecretString::new(),
+            current_otp: SecretString::new(),
+            confirm_buffer: SecretString::new(),
+            locked_until: None,
+            master_safe: (0, Vec::new()),
+            rated_master: SecretString::new(),
+            master_rating: (0, Vec::new()),
+            error_message: String::new()
         }
     }
+    /// The unlocked vault, only called from screens that are reached after unlocking
+    fn unlocked(&self) -> &VaultCache {
+        self.unlocked.as_ref().expect("Vault is locked")
+    }
+    /// Lock the vault, dropping the cache wipes the data key and every decrypted entry
+    fn lock(&mut self) {
+        // Nothing copied from the vault should outlive it being unlocked
+        self.clipboard.clear();
+        self.unlocked = None;
+        self.vault_changes = None;
+        self.search_query.clear();
+        self.search_results.clear();
+        self.selected_folder = None;
+        self.folder_counts.clear();
+        self.folder_name.clear();
+        self.renaming_folder = None;
+        self.password_history.clear();
+        self.undo_trash = None;
+        self.text_buffer.clear();
+        self.clear_current_entry();
+    }
     /// This function will display the login screen, where the user will enter their username
-    /// Depending on the username, the user will be taken to the insert master screen or the user not found screen
+    /// The username isn't looked up here, so whether an account exists is only ever checked together with its master password
+    /// If the account was locked out, the remaining time is shown
     fn login_screen(&mut self, ui: &mut egui::Ui) {
         ui.label("Please enter a Username");
         ui.text_edit_singleline(&mut self.account);
 
-        if ui.button("Submit").clicked() || ui.input(|i| i.key_pressed(egui::Key::Enter)) {
-            self.user_id = storage_options_sql::get_user_id(&self.account)
-                .expect("Failed to get user id");
-            if self.user_id == 0 {
-                self.current_screen = Screen::UserNotFound;
+        if let Some(locked_until) = self.locked_until {
+            let remaining = locked_until.saturating_duration_since(std::time::Instant::now()).as_secs();
+            if remaining > 0 {
+                ui.label(RichText::new(format!("Too many incorrect attempts, try again in {}", format_duration(remaining))).color(Color32::RED));
+                // Keep the countdown ticking even if the mouse isn't moving
+                ui.ctx().request_repaint_after(std::time::Duration::from_secs(1));
             } else {
-                self.current_screen = Screen::InsertMaster;
+                self.locked_until = None;
             }
+        }
+
+        if !self.error_message.is_empty() {
+            ui.label(RichText::new(&self.error_message).color(Color32::RED));
+        }
+
+        if ui.button("Submit").clicked() || ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+            self.error_message.clear();
+            self.current_screen = Screen::InsertMaster;
         };
+        if ui.button("Create a new user").clicked() {
+            self.error_message.clear();
+            self.current_screen = Screen::EnterNewMaster;
+        }
+
+        // Switching vaults is only possible while locked, so an unlocked key never points at the wrong file
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.label("Vault file: ");
+            ui.text_edit_singleline(&mut self.vault_path);
+            if ui.button("Open vault").clicked() {
+                self.open_vault();
+            }
+        });
     }
-    /// This function will display the user not found screen, where the user will be asked if they want to create a new user
-    /// If they do, they will be taken to the enter new master screen where the inserted username is used as a username
-    fn user_not_found_screen(&mut self, ui: &mut egui::Ui) {
-        ui.label("User not found, would you like to create a new user?");
-        if ui.button("Yes").clicked() {
-           self.current_screen = Screen::EnterNewMaster;      
+    /// Switch to the vault file typed on the login screen, creating it if it doesn't exist yet
+    fn open_vault(&mut self) {
+        match Vault::open(self.vault_path.trim()) {
+            Ok(vault) => {
+                self.vault = vault;
+                self.vault_path = self.vault.path().display().to_string();
+                self.min_master_score = self.vault.min_master_score();
+                self.auto_lock = self.vault.auto_lock();
+                self.reveal_timeout = self.vault.reveal_timeout();
+                self.locked_until = None;
+                self.error_message.clear();
+            }
+            Err(error) => self.error_message = format!("Could not open the vault: {}", error),
         }
-        if ui.button("No").clicked() {
-            println!("User not found");
-            self.current_screen = Screen::Login;
+    }
+    /// Lock the vault when nothing was done for too long, or the window lost focus or was minimized if the vault asks for that
+    /// Runs at the start of every update, before anything is drawn with the vault unlocked
+    fn check_auto_lock(&mut self, ctx: &egui::Context) {
+        if self.unlocked.is_none() {
             return;
         }
-    } 
+
+        let (active, focused, minimized) = ctx.input(|i| (!i.events.is_empty() || i.pointer.is_moving(), i.viewport().focused, i.viewport().minimized));
+        if active {
+            self.last_activity = std::time::Instant::now();
+        }
+
+        let message = if self.auto_lock.on_minimize && minimized == Some(true) {
+            "The vault was locked when its window was minimized".to_string()
+        } else if self.auto_lock.on_focus_loss && focused == Some(false) {
+            "The vault was locked when you switched to another window".to_string()
+        } else if self.auto_lock.idle_minutes > 0 {
+            let timeout = std::time::Duration::from_secs(u64::from(self.auto_lock.idle_minutes) * 60);
+            let idle = self.last_activity.elapsed();
+            if idle < timeout {
+                // Nothing else repaints an idle window, so wake up when the timeout runs out
+                ctx.request_repaint_after(timeout - idle);
+                return;
+            }
+            format!("The vault was locked after {} minutes without activity", self.auto_lock.idle_minutes)
+        } else {
+            return;
+        };
+
+        // The username is kept, so getting back in only takes the master password
+        self.lock();
+        self.clear_current_entry();
+        self.clear_new_master();
+        self.display_incorrect_msg = false;
+        self.lock_message = message;
+        self.current_screen = Screen::InsertMaster;
+    }
+    /// Send the user back to the login screen with a countdown until they can try their master password again
+    fn lock_out(&mut self, remaining: u64) {
+        self.locked_until = Some(std::time::Instant::now() + std::time::Duration::from_secs(remaining));
+        self.text_buffer.clear();
+        self.lock_message.clear();
+        self.display_incorrect_msg = false;
+        self.current_screen = Screen::Login;
+    }
     /// This function will display the enter new master screen, where the user will enter a new master password
-    /// and with it, an account is created
+    /// and with it, an account is created using the username entered on the login screen
     fn enter_new_master_screen(&mut self, ui: &mut egui::Ui) {
+        ui.label(format!("Creating the user {}", self.account));
         ui.label("Please enter the new master password: ");
-        ui.text_edit_singleline(&mut self.text_buffer);
-        let master_password = self.text_buffer.clone();
+        secret_edit(ui, "new master", &mut self.text_buffer, self.reveal_timeout);
+        self.master_strength_meter(ui);
+
+        ui.label("Please confirm the new master password: ");
+        secret_edit(ui, "confirm master", &mut self.confirm_buffer, self.reveal_timeout);
 
-        // Add password confirmation here
+        if !self.error_message.is_empty() {
+            ui.label(RichText::new(&self.error_message).color(Color32::RED));
+        }
 
         if ui.button("Submit").clicked() || ui.input(|i| i.key_pressed(egui::Key::Enter)) {
-            self.text_buffer.clear();
-            // generate a random salt
-            let salt = rand::thread_rng().gen::<[u8; 32]>();
-            let hashed_master = encryption_algorithms::hash_master(&master_password, salt);
-            self.hashed_master = hashed_master;
-            // Generate another random salt to serve as the kdf salt
-            let mut kdf_salt = rand::thread_rng().gen::<[u8; 32]>();
-            while kdf_salt == salt {
-                kdf_salt = rand::thread_rng().gen::<[u8; 32]>();
-            }
-            self.user_id = storage_options_sql::add_user_id(self.account.as_str(), &hashed_master, &salt, &kdf_salt).expect("Failed to add user_id");
+            if let Err(message) = password_generator::check_new_master_password(self.text_buffer.expose(), self.confirm_buffer.expose(), self.min_master_score) {
+                self.error_message = message;
+                return;
+            }
+            // The salts and hashes are all generated in storage, the GUI only ever sees the password
+            match self.vault.add_user_id(self.account.as_str(), self.text_buffer.expose()) {
+                Ok(_) => {
+                    self.clear_new_master();
+                    self.current_screen = Screen::Login;
+                }
+                Err(error) => self.error_message = format!("Could not create the account: {}", error),
+            }
+        }
+
+        if ui.button("Cancel").clicked() {
+            self.clear_new_master();
             self.current_screen = Screen::Login;
-        }              
+        }
+    }
+    /// Show how strong the new master password being typed is, and what would make it stronger
+    fn master_strength_meter(&mut self, ui: &mut egui::Ui) {
+        if self.rated_master != self.text_buffer {
+            self.rated_master = self.text_buffer.clone();
+            self.master_rating = password_generator::check_password_safety(self.text_buffer.expose());
+        }
+        if self.text_buffer.expose().is_empty() {
+            return;
+        }
+
+        let (safety_rating, safety_message) = &self.master_rating;
+        let color = if *safety_rating < self.min_master_score {
+            Color32::RED
+        } else if *safety_rating < 50 {
+            Color32::YELLOW
+        } else {
+            Color32::GREEN
+        };
+        ui.add(egui::ProgressBar::new(*safety_rating as f32 / 50.0)
+            .fill(color)
+            .desired_width(250.0)
+            .text(RichText::new(format!("Strength: {}/50, at least {} needed", safety_rating, self.min_master_score)).color(Color32::BLACK)));
+        for message in safety_message {
+            ui.label(RichText::new(message).color(Color32::RED).size(12.5));
+        }
+    }
+    /// Wipe the new master password, its confirmation and its rating
+    fn clear_new_master(&mut self) {
+        self.text_buffer.clear();
+        self.confirm_buffer.clear();
+        self.rated_master.clear();
+        self.master_rating = (0, Vec::new());
+        self.error_message.clear();
     }
     /// This function will display the change master password screen, where the user will enter their new master password
     /// and with it, the master password will be changed
@@ -122,128 +374,156 @@ impl PasswordManagerApp {
         }
 
         ui.label("Please enter the new master password: ");
-        ui.text_edit_singleline(&mut self.text_buffer);
-        let master_password = self.text_buffer.clone();
+        secret_edit(ui, "new master", &mut self.text_buffer, self.reveal_timeout);
+        self.master_strength_meter(ui);
 
         ui.label("Please confirm the new master password: ");
-        ui.text_edit_singleline(&mut self.current_password);
+        secret_edit(ui, "confirm master", &mut self.confirm_buffer, self.reveal_timeout);
 
-        // Add password confirmation here
-
-        if (ui.button("Submit").clicked() || ui.input(|i| i.key_pressed(egui::Key::Enter))) && self.text_buffer == master_password {
-            self.current_password.clear();
-
-            // Generate a new random salt
-            let salt = rand::thread_rng().gen::<[u8; 32]>();
-            let hashed_master = encryption_algorithms::hash_master(&master_password, salt);
+        if !self.error_message.is_empty() {
+            ui.label(RichText::new(&self.error_message).color(Color32::RED));
+        }
 
-            // Generate a new random kdf salt
-            let mut kdf_salt = rand::thread_rng().gen::<[u8; 32]>();
-            while kdf_salt == salt {
-                kdf_salt = rand::thread_rng().gen::<[u8; 32]>();
+        if ui.button("Submit").clicked() || ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+            if let Err(message) = password_generator::check_new_master_password(self.text_buffer.expose(), self.confirm_buffer.expose(), self.min_master_score) {
+                self.error_message = message;
+                return;
             }
 
-            storage_options_sql::change_master_password(self.user_id, &self.hashed_master, &hashed_master, &salt, &kdf_salt);
+            // Only the wrapped data key changes, the entries themselves don't need to be reencrypted
+            let unlocked = self.unlocked();
+            if let Err(error) = self.vault.change_master_password(unlocked.user_id(), unlocked.data_key(), self.text_buffer.expose()) {
+                self.error_message = format!("Could not change the master password: {}", error);
+                return;
+            }
 
-            self.hashed_master = hashed_master;
-            self.text_buffer.clear();
+            self.clear_new_master();
+            self.lock();
             self.current_screen = Screen::Login;
-        }      
+        }
 
         if ui.button("Cancel").clicked() {
+            self.clear_new_master();
             self.current_screen = Screen::Main;
         }
-    }       
+    }
     
     /// This function will display the insert master screen, where the user will enter their master password
     /// If the master password is correct, the user will be taken to the main screen
     /// If the master password is incorrect, the user will be shown an error message and be given a chance it insert it again
-    /// A locking mechanism at the SQL level will be introduced in the future to prevent brute force attacks
-    /// For now, it returns you to the login screen after 10 attempts
+    /// Failed attempts are counted in the database, and after too many the account is locked out for a growing amount of time
     /// Since the database is stored locally, brute force attacks can be done regardless of this software, but in the cloud
     /// It will be implemented to prevent any type of brute force attacks
     fn insert_master_screen(&mut self, ui: &mut egui::Ui) {
-        ui.label("Please enter the master password: ");
-        ui.text_edit_singleline(&mut self.text_buffer);
-
-        if self.password_attempts > 20 && self.password_limit == true {
-            // I'm going to implement a locking technique here stored in the file, that calculates the current date
-            // And adds a certain amount of time to it, and if the current date is less than that time, the user is locked out
-            self.current_screen = Screen::Login;
-            self.password_attempts = 0;
-            self.text_buffer.clear();
-            self.hashed_master = [0; 32];
-            self.user_id = 0;
-            self.account.clear();
-            self.salt = [0; 32];
-            self.display_incorrect_msg = false;
-            return;
+        if !self.lock_message.is_empty() {
+            ui.label(&self.lock_message);
         }
+        ui.label(format!("Please enter the master password for {}: ", self.account));
+        secret_edit(ui, "master", &mut self.text_buffer, self.reveal_timeout);
 
         if self.display_incorrect_msg {
-            ui.label("Incorrect master password, please try again");
+            ui.label("Incorrect username or master password, please try again");
+        }
+
+        if !self.error_message.is_empty() {
+            ui.label(RichText::new(&self.error_message).color(Color32::RED));
         }
 
         if ui.button("Submit").clicked() || ui.input(|i| i.key_pressed(egui::Key::Enter)) {
-            self.password_attempts += 1;
             let master_password = self.text_buffer.clone();
+            self.text_buffer.clear();
 
-            let salt = storage_options_sql::get_salt(self.user_id); 
-            for i in 0..32 {
-                self.salt[i] = salt[i];
+            // Storage checks the lockout, the master password and unlocks the vault, the GUI never sees any hashes or salts
+            self.error_message.clear();
+            match self.vault.verify_master(&self.account, master_password.expose()) {
+                Ok(unlocked) => {
+                    // The vault still unlocks with the legacy key, so this is only worth mentioning
+                    if let Some(error) = unlocked.upgrade_error {
+                        self.error_message = format!("Could not upgrade the account to Argon2id, it will be tried again next time: {}", error);
+                    }
+                    // Everything is decrypted once here, the main screen works from memory after this
+                    let mut cache = VaultCache::load(&self.vault, unlocked);
+                    self.vault_changes = Some(cache.subscribe());
+                    self.search_results = cache.search("");
+                    self.folder_counts = cache.folder_counts();
+                    self.clipboard_timeout = cache.clipboard_timeout(&self.vault);
+                    self.search_query.clear();
+                    self.last_activity = std::time::Instant::now();
+                    self.unlocked = Some(cache);
+                }
+                Err(storage_options_sql::AuthError::LockedOut(remaining)) => {
+                    self.lock_out(remaining);
+                    return;
+                }
+                Err(storage_options_sql::AuthError::InvalidCredentials) => {
+                    self.display_incorrect_msg = true;
+                    return;
+                }
+                Err(storage_options_sql::AuthError::Crypto(error)) => {
+                    self.error_message = format!("Could not unlock the vault: {}", error);
+                    return;
+                }
             }
+            self.lock_message.clear();
+            self.master_safe = password_generator::check_password_safety(master_password.expose());
+            self.display_incorrect_msg = false;
+            self.current_screen = Screen::Main;
+        }
 
+        if ui.button("Back").clicked() {
             self.text_buffer.clear();
-            let hashed_master = encryption_algorithms::hash_master(&master_password, self.salt);
-            self.hashed_master = hashed_master;
-            let hashed_master_vec = hashed_master.to_vec();
-            let hashed_user_master = storage_options_sql::get_hashed_master(self.user_id);
-
-            if hashed_master_vec != hashed_user_master {
-                self.hashed_master = [0; 32];
-                self.display_incorrect_msg = true;
-                println!("Incorrect master password");
-                return;
-            } else {
-                self.master_safe = password_generator::check_password_safety(&master_password);
-                self.password_attempts = 0;
-                self.display_incorrect_msg = false;
-                // Change the hash to be the KDF hash, so that the stored hash cannot actually decrypt anything
-                let kdf_salt = storage_options_sql::get_kdf_salt(self.user_id);
-                for i in 0..32 {
-                    self.kdf_salt[i] = kdf_salt[i];
-                }
-                self.hashed_master = encryption_algorithms::hash_master(&master_password, self.kdf_salt);
-                self.current_screen = Screen::Main;
-            }
+            self.error_message.clear();
+            self.lock_message.clear();
+            self.display_incorrect_msg = false;
+            self.current_screen = Screen::Login;
         }
     }
     /// This function will display the main screen, where the user can add a password, check for compromised passwords,
     /// get any password, change any password, delete any password, exit the application
     /// The check for compromised passwords is not implemented yet
     fn main_screen(&mut self, ui: &mut egui::Ui) {
-        self.current_account.clear();
-        self.current_website.clear();
-        self.current_password.clear();
+        let vault_changed = self.vault_changes.as_ref().is_some_and(|changes| changes.try_iter().count() > 0);
+        if vault_changed {
+            self.folder_counts = self.unlocked().folder_counts();
+        }
+        let folder_changed = self.folder_sidebar(ui);
 
-        if ui.button("Add a password").clicked() {
+        if ui.button("Add an entry").clicked() {
+            self.error_message.clear();
+            // New entries go in the folder that is open
+            self.current_entry.folder_id = self.selected_folder;
             self.current_screen = Screen::AddPassword;
         }
 
-        if self.master_safe.0 < 30 {
+        if self.master_safe.0 < self.min_master_score {
             ui.label(RichText::new("Your master password is not safe, please change it").color(Color32::RED).size(12.5));
         }
 
         if ui.button("Change Master Password").clicked() {
+            self.error_message.clear();
             self.current_screen = Screen::ChangeMasterPassword;
         }
 
+        if ui.button(format!("Trash ({})", self.unlocked().trash().len())).clicked() {
+            // The setting is read once here rather than on every repaint of the trash screen
+            self.trash_retention_days = self.unlocked().trash_retention_days(&self.vault);
+            self.undo_trash = None;
+            self.error_message.clear();
+            self.current_screen = Screen::Trash;
+        }
+
+        if ui.button("Settings").clicked() {
+            // The settings are edited on a copy, so nothing changes until they are saved
+            self.settings_min_master_score = self.min_master_score;
+            self.settings_auto_lock = self.auto_lock;
+            self.settings_reveal_timeout = self.reveal_timeout;
+            self.error_message.clear();
+            self.current_screen = Screen::Settings;
+        }
+
         if ui.button("Exit").clicked() {
-            self.hashed_master = [0; 32];
-            self.text_buffer.clear();
-            self.user_id = 0;
+            self.lock();
             self.account.clear();
-            self.salt = [0; 32];
             self.current_screen = Screen::Login;
         } else {
             // if ui.button("Check for compromised passwords").clicked() {
@@ -255,112 +535,706 @@ impl PasswordManagerApp {
                 ui.text_edit_singleline(&mut self.text_buffer);
             });
 
-            let mut account_list = storage_options_sql::get_accounts(&self.hashed_master, self.user_id);
-            // Filter the accounts to match the text_buffer, storing the indices of matching account triplets
-            let mut indices = Vec::new();
-            for i in 0..account_list[0].len() {
-                // Make a copy of the text_buffer that is all lowercase and convert the account names to lowercase
-                let text_buffer_lower = self.text_buffer.to_lowercase();
-                let account_lower = account_list[0][i].to_lowercase();
-                let website_lower = account_list[1][i].to_lowercase();
-                if account_lower.contains(&text_buffer_lower) || website_lower.contains(&text_buffer_lower) {
-                    indices.push(i);
+            // Only search again when the query, the folder or the vault changed, not on every repaint
+            if vault_changed || folder_changed || self.search_query != self.text_buffer {
+                self.search_query = self.text_buffer.clone();
+                let unlocked = self.unlocked.as_ref().expect("Vault is locked");
+                self.search_results = unlocked.search(self.search_query.expose());
+                if let Some(folder_id) = self.selected_folder {
+                    self.search_results.retain(|&index| unlocked.is_in_folder(index, folder_id));
                 }
             }
 
-            // Create a new account list with only the matching accounts
-            account_list = [
-                indices.iter().map(|&i| account_list[0][i].clone()).collect(),
-                indices.iter().map(|&i| account_list[1][i].clone()).collect(),
-                indices.iter().map(|&i| account_list[2][i].clone()).collect()
-            ];
-
-            if account_list[0].is_empty() {
+            if self.search_results.is_empty() {
                 ui.label("No accounts found");
             }
 
-            for (i, account) in account_list[0].iter().enumerate() {
-                ui.horizontal(|ui| {
-                    ui.label(account);
-                    ui.label(account_list[1][i].as_str());
-                    if ui.button("Get Password").clicked() {
-                        self.current_account =  account_list[0][i].clone();
-                        self.current_website = account_list[1][i].clone();
-                        self.current_password = account_list[2][i].clone();
+            if !self.error_message.is_empty() {
+                ui.label(RichText::new(&self.error_message).color(Color32::RED));
+            }
+
+            // Offer to undo the last delete for a few seconds
+            if let Some((entry_id, deleted)) = self.undo_trash {
+                let remaining = UNDO_TIMEOUT.saturating_sub(deleted.elapsed());
+                if remaining.is_zero() {
+                    self.undo_trash = None;
+                } else {
+                    ui.horizontal(|ui| {
+                        ui.label("Entry moved to the trash");
+                        if ui.button("Undo").clicked() {
+                            if let Err(error) = self.unlocked.as_mut().expect("Vault is locked").restore(&self.vault, entry_id) {
+                                self.error_message = format!("The entry could not be restored: {}", error);
+                            }
+                            self.undo_trash = None;
+                        }
+                    });
+                    // Repaint once the toast should disappear even if nothing else happens
+                    ui.ctx().request_repaint_after(remaining);
+                }
+            }
+
+            // Only the rows that are scrolled into view are laid out, so large vaults stay responsive
+            let mut action = None;
+            let row_height = ui.spacing().interact_size.y;
+            let unlocked = self.unlocked.as_ref().expect("Vault is locked");
+            egui::ScrollArea::vertical().show_rows(ui, row_height, self.search_results.len(), |ui, rows| {
+                for &index in &self.search_results[rows] {
+                    ui.horizontal(|ui| {
+                        // An entry that fails to decrypt gets a marker instead of taking down the whole app
+                        let entry = unlocked.entry(index);
+                        let (Ok(account), Some(name)) = (&entry.account, entry.display_name()) else {
+                            ui.label(RichText::new("Corrupted entry, it could not be decrypted").color(Color32::RED));
+                            return;
+                        };
+                        if ui.selectable_label(entry.favorite, if entry.favorite { "★" } else { "☆" }).clicked() {
+                            action = Some((EntryAction::Favorite, entry.id));
+                        }
+                        // Entries are filed by dragging them onto a folder in the sidebar
+                        ui.dnd_drag_source(egui::Id::new(("drag entry", entry.id)), Dragged::Entry(entry.id), |ui| ui.label(name))
+                            .response.on_hover_text("Drag onto a folder to move it there");
+                        ui.label(RichText::new(entry.kind.name()).size(12.5));
+                        if entry.kind.has_account() {
+                            ui.label(account.expose());
+                        }
+                        if ui.button("Get Password").clicked() {
+                            action = Some((EntryAction::Get, entry.id));
+                        }
+                        if ui.button("Change Password").clicked() {
+                            action = Some((EntryAction::Change, entry.id));
+                        }
+                        if ui.button("Delete Password").clicked() {
+                            action = Some((EntryAction::Delete, entry.id));
+                        }
+                    });
+                }
+            });
+
+            // The buttons only record what was clicked, since the cache can't change while its entries are borrowed
+            if let Some((action, entry_id)) = action {
+                self.error_message.clear();
+                let unlocked = self.unlocked.as_mut().expect("Vault is locked");
+                let entry = unlocked.find(entry_id).expect("Entry is in the cache");
+                match action {
+                    EntryAction::Get | EntryAction::Change => {
+                        // Entries that fail to decrypt completely can't be opened, the rest show whatever did decrypt
+                        match entry.data() {
+                            Ok(data) => {
+                                self.current_tags = entry::join_tags(&data.details.tags);
+                                self.current_otp = data.details.otp.as_ref().map(OtpParams::to_uri).unwrap_or_default();
+                                self.current_entry = data;
+                                self.selected_entry = Some(entry_id);
+                            }
+                            Err(error) => {
+                                self.error_message = format!("The entry could not be opened: {}", error);
+                                return;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                match action {
+                    EntryAction::Favorite => {
+                        let favorite = !entry.favorite;
+                        unlocked.set_favorite(&self.vault, entry_id, favorite);
+                    }
+                    EntryAction::Get => {
+                        // Only the names and sizes are read here, the files are decrypted when they are exported
+                        self.attachments = unlocked.attachments(&self.vault, entry_id);
                         self.current_screen = Screen::GetPassword;
                     }
-                    if ui.button("Change Password").clicked() {
-                        self.current_account =  account_list[0][i].clone();
-                        self.current_website = account_list[1][i].clone();
-                        self.current_password = account_list[2][i].clone();
-                        storage_options_sql::remove_password(storage_options_sql::find_entry_id(self.user_id, account.as_str(), account_list[2][i].as_str(), account_list[1][i].as_str(), &self.hashed_master))
-                            .expect("Failed to delete password");
-                        self.current_screen = Screen::AddPassword;
+                    EntryAction::Change => {
+                        // The history is read once here rather than on every repaint of the edit screen
+                        self.password_history = unlocked.password_history(&self.vault, entry_id);
+                        self.current_screen = Screen::EditPassword;
                     }
-                    if ui.button("Delete Password").clicked() {
-                        let entry_id = storage_options_sql::find_entry_id(self.user_id, account.as_str(), account_list[2][i].as_str(), account_list[1][i].as_str(), &self.hashed_master);
-                        storage_options_sql::remove_password(entry_id)
-                            .expect("Failed to delete password");
+                    EntryAction::Delete => {
+                        match unlocked.trash_entry(&self.vault, entry_id) {
+                            Ok(()) => self.undo_trash = Some((entry_id, std::time::Instant::now())),
+                            Err(error) => self.error_message = format!("The entry could not be moved to the trash: {}", error),
+                        }
                     }
-                });
+                }
             }
         }
 
     }
 
-    /// This function will display the add password screen, where the user will enter the account name, website, and password
+    /// Show the folder tree next to the entries, with how many entries are in each folder
+    /// Entries and folders are moved by dragging them onto a folder, or onto "All entries" to take them out of every folder
+    /// Returns true if another folder was opened
+    fn folder_sidebar(&mut self, ui: &mut egui::Ui) -> bool {
+        let mut action = None;
+        let unlocked = self.unlocked.as_ref().expect("Vault is locked");
+
+        egui::SidePanel::left("folders").resizable(true).default_width(180.0).show_inside(ui, |ui| {
+            ui.label("Folders");
+            egui::ScrollArea::vertical().max_height(ui.available_height() - 90.0).show(ui, |ui| {
+                let all = ui.selectable_label(self.selected_folder.is_none(), format!("All entries ({})", unlocked.entry_count()));
+                if all.clicked() {
+                    action = Some(FolderAction::Select(None));
+                }
+                folder_drop_target(ui, &all, None, &mut action);
+                folder_tree(ui, unlocked.folders(), None, &self.folder_counts, self.selected_folder, &mut action);
+            });
+
+            ui.separator();
+            let hint = if self.renaming_folder.is_some() { "New name" } else { "New folder" };
+            let response = ui.add(egui::TextEdit::singleline(&mut self.folder_name).hint_text(hint));
+            let entered = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            ui.horizontal(|ui| {
+                if self.renaming_folder.is_some() {
+                    if ui.button("Rename").clicked() || entered {
+                        action = Some(FolderAction::Rename);
+                    }
+                    if ui.button("Cancel").clicked() {
+                        action = Some(FolderAction::CancelRename);
+                    }
+                } else if ui.button("Add folder").on_hover_text("Added inside the open folder").clicked() || entered {
+                    action = Some(FolderAction::Add);
+                }
+            });
+        });
+
+        let Some(action) = action else {
+            return false;
+        };
+        let previous_folder = self.selected_folder;
+        let unlocked = self.unlocked.as_mut().expect("Vault is locked");
+        let result = match action {
+            FolderAction::Select(folder_id) => {
+                self.selected_folder = folder_id;
+                Ok(())
+            }
+            FolderAction::MoveEntry(entry_id, folder_id) => unlocked.move_entry(&self.vault, entry_id, folder_id),
+            FolderAction::MoveFolder(folder_id, parent_id) => unlocked.move_folder(&self.vault, folder_id, parent_id),
+            FolderAction::StartRename(folder_id) => {
+                let folder = unlocked.folders().iter().find(|folder| folder.id == folder_id).expect("Folder is in the cache");
+                self.folder_name = folder.name.clone().unwrap_or_default();
+                self.renaming_folder = Some(folder_id);
+                Ok(())
+            }
+            FolderAction::Rename => {
+                let folder_id = self.renaming_folder.take().expect("No folder is being renamed");
+                let result = unlocked.rename_folder(&self.vault, folder_id, &self.folder_name);
+                self.folder_name.clear();
+                result
+            }
+            FolderAction::CancelRename => {
+                self.renaming_folder = None;
+                self.folder_name.clear();
+                Ok(())
+            }
+            FolderAction::Add if self.folder_name.expose().trim().is_empty() => Ok(()),
+            FolderAction::Add => {
+                let result = unlocked.add_folder(&self.vault, self.selected_folder, &self.folder_name).map(|_| ());
+                self.folder_name.clear();
+                result
+            }
+            FolderAction::Remove(folder_id) => {
+                // The open folder falls back to showing everything once it is gone
+                if self.selected_folder == Some(folder_id) {
+                    self.selected_folder = None;
+                }
+                unlocked.remove_folder(&self.vault, folder_id)
+            }
+        };
+
+        match result {
+            Ok(()) => self.error_message.clear(),
+            Err(error) => self.error_message = format!("The folder could not be changed: {}", error),
+        }
+        self.selected_folder != previous_folder
+    }
+
+    /// This function will display the add password screen, where the user will enter the details of a new entry
     fn add_password_screen(&mut self, ui: &mut egui::Ui) {
-        ui.label("Please enter the account name: ");
-        ui.text_edit_singleline(&mut self.current_account);
+        let submitted = egui::ScrollArea::vertical().max_height(ui.available_height() - 60.0).show(ui, |ui| {
+            // Picking another kind starts its fields over, the fields every kind has are kept
+            let mut kind = self.current_entry.payload.kind();
+            egui::ComboBox::from_label("Kind of entry").selected_text(kind.name()).show_ui(ui, |ui| {
+                for option in EntryKind::ALL {
+                    ui.selectable_value(&mut kind, option, option.name());
+                }
+            });
+            if kind != self.current_entry.payload.kind() {
+                self.current_entry.payload = Payload::new(kind);
+            }
+
+            entry_form(ui, &mut self.current_entry, &mut self.current_tags, &mut self.current_otp, self.reveal_timeout)
+        }).inner;
+
+        if !self.error_message.is_empty() {
+            ui.label(RichText::new(&self.error_message).color(Color32::RED));
+        }
+        if (ui.button("Submit").clicked() || submitted) && self.validate_current_entry() {
+            self.current_entry.details.tags = entry::parse_tags(self.current_tags.expose());
+            let unlocked = self.unlocked.as_mut().expect("Vault is locked");
+            // The form stays filled in if the entry couldn't be saved, so nothing typed is lost
+            match unlocked.add(&self.vault, &self.current_entry) {
+                Ok(_) => {
+                    self.error_message.clear();
+                    self.clear_current_entry();
+                    self.current_screen = Screen::Main;
+                }
+                Err(error) => self.error_message = format!("The entry could not be added: {}", error),
+            }
+        }
+        if ui.button("Cancel").clicked() {
+            self.error_message.clear();
+            self.clear_current_entry();
+            self.current_screen = Screen::Main;
+        }
+    }
+    /// Check the fields of the entry being added or edited, showing what is wrong with them
+    /// The two-factor URI is parsed here too, so a mistyped one is reported instead of being dropped
+    fn validate_current_entry(&mut self) -> bool {
+        let otp = match self.current_otp.expose().trim() {
+            "" => Ok(None),
+            uri => OtpParams::from_uri(uri).map(Some).map_err(|error| format!("The two-factor secret could not be read: {}", error)),
+        };
+        match otp.and_then(|otp| self.current_entry.payload.validate().map(|()| otp)) {
+            Ok(otp) => {
+                self.current_entry.details.otp = otp;
+                self.error_message.clear();
+                true
+            }
+            Err(message) => {
+                self.error_message = message;
+                false
+            }
+        }
+    }
+    /// This function will display the edit password screen, filled in with the current details of the entry
+    /// Nothing is written until the user saves, and the password being replaced is kept in the entry's history
+    fn edit_password_screen(&mut self, ui: &mut egui::Ui) {
+        let mut save = false;
+        let mut leave = false;
+        egui::ScrollArea::vertical().max_height(ui.available_height() - 60.0).show(ui, |ui| {
+            ui.label(format!("Kind of entry: {}", self.current_entry.payload.kind().name()));
+            if entry_form(ui, &mut self.current_entry, &mut self.current_tags, &mut self.current_otp, self.reveal_timeout) {
+                save = true;
+            }
 
-        ui.label("Please enter the website: ");
-        ui.text_edit_singleline(&mut self.current_website);
+            // Restoring only fills in the password field, it is saved like any other edit so the current password is kept too
+            if !self.password_history.is_empty() {
+                ui.separator();
+                ui.label(format!("Previous values of the {}: ", self.current_entry.payload.kind().password_label().to_lowercase()));
+                for (index, old) in self.password_history.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        let Ok(password) = &old.password else {
+                            ui.label(RichText::new("Corrupted password, it could not be decrypted").color(Color32::RED));
+                            return;
+                        };
+                        secret_label(ui, ("previous password", index), password, self.reveal_timeout);
+                        ui.label(format!("replaced {}", format_age(Some(old.changed_at))));
+                        if ui.button("Restore").clicked() {
+                            self.current_entry.password = password.clone();
+                        }
+                    });
+                }
+            }
+        });
 
-        ui.label("Please enter the password: ");
-        ui.text_edit_singleline(&mut self.current_password);
-        if ui.button("Generate password").clicked() {
-            self.current_password = password_generator::generate_password(20);
+        if !self.error_message.is_empty() {
+            ui.label(RichText::new(&self.error_message).color(Color32::RED));
+        }
+        if (ui.button("Save").clicked() || save) && self.validate_current_entry() {
+            let entry_id = self.selected_entry.expect("No entry is being edited");
+            self.current_entry.details.tags = entry::parse_tags(self.current_tags.expose());
+            let unlocked = self.unlocked.as_mut().expect("Vault is locked");
+            match unlocked.update(&self.vault, entry_id, &self.current_entry) {
+                Ok(()) => leave = true,
+                Err(error) => self.error_message = format!("The entry could not be saved: {}", error),
+            }
+        }
+        if ui.button("Cancel").clicked() {
+            leave = true;
         }
 
-        if ui.button("Submit").clicked() || ui.input(|i| i.key_pressed(egui::Key::Enter)) {
-            storage_options_sql::add_password(self.user_id, &self.current_account, &self.current_password, &self.hashed_master, &self.current_website)
-                .expect("Failed to add password");
-            self.current_account.clear();
-            self.current_website.clear();
-            self.current_password.clear();
+        if leave {
+            self.error_message.clear();
+            self.password_history.clear();
+            self.clear_current_entry();
             self.current_screen = Screen::Main;
         }
     }
-    /// This function will display the get password screen, where the user will be shown the password for the account and website
+    /// Wipe the entry being viewed or edited
+    fn clear_current_entry(&mut self) {
+        self.current_entry = EntryData::default();
+        self.current_tags.clear();
+        self.current_otp.clear();
+        self.attachments.clear();
+        self.attachment_path.clear();
+        self.attachment_message.clear();
+        self.confirm_remove_attachment = None;
+        self.reading = None;
+        self.selected_entry = None;
+    }
+    /// This function will display the settings screen, with the settings that apply to the whole vault file rather than one account
+    /// These are when the vault locks itself, how long passwords stay revealed and the minimum master password strength
+    /// The minimum applies to every account in the vault, so it can only be changed by someone who has unlocked it
+    fn settings_screen(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Lock the vault after (minutes without activity, 0 never locks it): ");
+            ui.add(egui::DragValue::new(&mut self.settings_auto_lock.idle_minutes).range(0..=1440));
+        });
+        ui.checkbox(&mut self.settings_auto_lock.on_focus_loss, "Lock the vault when switching to another window");
+        ui.checkbox(&mut self.settings_auto_lock.on_minimize, "Lock the vault when its window is minimized");
+
+        ui.horizontal(|ui| {
+            ui.label("Hide revealed passwords again after (seconds, 0 keeps them shown): ");
+            ui.add(egui::DragValue::new(&mut self.settings_reveal_timeout).range(0..=3600));
+        });
+        ui.add(egui::Slider::new(&mut self.settings_min_master_score, 0..=50).text("Minimum strength of master passwords in this vault"));
+
+        ui.horizontal(|ui| {
+            if ui.button("Save").clicked() {
+                self.vault.set_auto_lock(&self.settings_auto_lock);
+                self.auto_lock = self.settings_auto_lock;
+                self.vault.set_reveal_timeout(self.settings_reveal_timeout);
+                self.reveal_timeout = self.settings_reveal_timeout;
+                self.vault.set_min_master_score(self.settings_min_master_score);
+                self.min_master_score = self.settings_min_master_score;
+                self.current_screen = Screen::Main;
+            }
+            if ui.button("Cancel").clicked() {
+                self.current_screen = Screen::Main;
+            }
+        });
+    }
+    /// This function will display the trash screen, where deleted entries can be restored or permanently deleted
+    /// Entries are purged automatically once they have been in the trash for longer than the retention period
+    fn trash_screen(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Keep deleted entries for (days, 0 keeps them until deleted by hand): ");
+            if ui.add(egui::DragValue::new(&mut self.trash_retention_days).range(0..=3650)).changed() {
+                let unlocked = self.unlocked.as_mut().expect("Vault is locked");
+                unlocked.set_trash_retention_days(&self.vault, self.trash_retention_days);
+            }
+        });
+
+        let unlocked = self.unlocked.as_ref().expect("Vault is locked");
+        if unlocked.trash().is_empty() {
+            ui.label("The trash is empty");
+        }
+        if !self.error_message.is_empty() {
+            ui.label(RichText::new(&self.error_message).color(Color32::RED));
+        }
+
+        // The buttons only record what was clicked, since the cache can't change while its entries are borrowed
+        let mut action = None;
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |time| time.as_secs());
+        egui::ScrollArea::vertical().max_height(ui.available_height() - 40.0).show(ui, |ui| {
+            for trashed in unlocked.trash() {
+                ui.horizontal(|ui| {
+                    match (&trashed.entry.account, &trashed.entry.website) {
+                        (Ok(account), Ok(website)) => {
+                            ui.label(account.expose());
+                            ui.label(website.expose());
+                        }
+                        _ => {
+                            ui.label(RichText::new("Corrupted entry, it could not be decrypted").color(Color32::RED));
+                        }
+                    }
+                    ui.label(format!("deleted {} ago", format_duration(now.saturating_sub(trashed.deleted_at as u64))));
+                    if ui.button("Restore").clicked() {
+                        action = Some((true, trashed.entry.id));
+                    }
+                    if ui.button("Delete forever").clicked() {
+                        action = Some((false, trashed.entry.id));
+                    }
+                });
+            }
+        });
+
+        if let Some((restore, entry_id)) = action {
+            let unlocked = self.unlocked.as_mut().expect("Vault is locked");
+            let result = if restore {
+                unlocked.restore(&self.vault, entry_id).map_err(|error| format!("The entry could not be restored: {}", error))
+            } else {
+                unlocked.purge(&self.vault, entry_id).map_err(|error| format!("The entry could not be deleted: {}", error))
+            };
+            match result {
+                Ok(()) => self.error_message.clear(),
+                Err(message) => self.error_message = message,
+            }
+        }
+
+        if ui.button("Back").clicked() {
+            self.error_message.clear();
+            self.current_screen = Screen::Main;
+        }
+    }
+    /// This function will display the get password screen, where the user will be shown everything stored in the entry
     fn get_password_screen(&mut self, ui: &mut egui::Ui) {
-        ui.label(format!("The password for {} on {} is: {}", self.current_account, self.current_website, self.current_password));
+        let entry_id = self.selected_entry.expect("No entry is selected");
+        let unlocked = self.unlocked.as_ref().expect("Vault is locked");
+        let entry = unlocked.find(entry_id).expect("Entry is in the cache");
+        let data = &self.current_entry;
+        let mut copy = None;
+        let mut read = false;
+        let mut next_hotp = false;
+        let mut attachment_action = None;
 
-        // Display password safety
-        let (safety_rating, safety_message) = password_generator::check_password_safety(&self.current_password);
-        ui.label(format!("Password safety rating: {}/50", safety_rating));
-        for message in safety_message {
-            ui.label(RichText::new(message).color(Color32::RED).size(12.5));
+        // Files dropped on the window are attached to the entry being viewed
+        let dropped = ui.ctx().input(|i| i.raw.dropped_files.iter().find_map(|file| file.path.clone()));
+        if let Some(path) = dropped {
+            attachment_action = Some(AttachmentAction::Attach(path));
+        }
+
+        egui::ScrollArea::vertical().max_height(ui.available_height() - 40.0).show(ui, |ui| {
+            let kind = data.payload.kind();
+            if !data.details.title.expose().is_empty() {
+                ui.heading(data.details.title.expose());
+            }
+            ui.label(kind.name());
+            if kind.has_account() {
+                ui.label(format!("The {} for {} on {} is:", kind.password_label().to_lowercase(), data.account.expose(), data.website.expose()));
+            } else {
+                ui.label(format!("{}: ", kind.password_label()));
+            }
+            ui.horizontal(|ui| {
+                secret_label(ui, ("password", entry_id), &data.password, self.reveal_timeout);
+                if ui.button("Show for reading").on_hover_text("Big characters, for typing the password on another device").clicked() {
+                    read = true;
+                }
+            });
+
+            // Display password safety, secrets the user didn't pick like PINs and tokens aren't rated
+            if kind.has_chosen_password() {
+                let (safety_rating, safety_message) = password_generator::check_password_safety(data.password.expose());
+                ui.label(format!("Password safety rating: {}/50", safety_rating));
+                for message in safety_message {
+                    ui.label(RichText::new(message).color(Color32::RED).size(12.5));
+                }
+            }
+
+            if ui.button("Copy to clipboard").clicked() {
+                copy = Some(data.password.clone());
+            }
+            if let Some(remaining) = self.clipboard.clears_in() {
+                ui.label(RichText::new(format!("The clipboard will be cleared in {} seconds", remaining.as_secs() + 1)).size(12.5));
+                ui.ctx().request_repaint_after(std::time::Duration::from_secs(1));
+            }
+            ui.horizontal(|ui| {
+                ui.label("Clear copies from the clipboard after (seconds, 0 never clears them): ");
+                if ui.add(egui::DragValue::new(&mut self.clipboard_timeout).range(0..=3600)).changed() {
+                    unlocked.set_clipboard_timeout(&self.vault, self.clipboard_timeout);
+                }
+            });
+
+            payload_view(ui, &data.payload, &mut copy, self.reveal_timeout);
+
+            // TOTP codes are shown live, HOTP codes are only made when asked for since each one uses up the counter
+            if let Some(otp) = &data.details.otp {
+                ui.separator();
+                let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |time| time.as_secs());
+                if let OtpCounter::Time { period } = otp.counter {
+                    let code = otp.code(now);
+                    let remaining = otp.seconds_remaining(now).unwrap_or(period);
+                    ui.horizontal(|ui| {
+                        ui.label(format!("Two-factor code: {}", code.expose()));
+                        ui.add(egui::ProgressBar::new(remaining as f32 / period as f32).desired_width(80.0).text(format!("{}s", remaining)));
+                        if ui.button("Copy code").clicked() {
+                            copy = Some(code.clone());
+                        }
+                    });
+                    // Repaint every second so the countdown and the code stay current
+                    ui.ctx().request_repaint_after(std::time::Duration::from_secs(1));
+                } else if ui.button("Copy next two-factor code").clicked() {
+                    next_hotp = true;
+                }
+            }
+            // if ui.button("Check if password has been compromised").clicked() {
+
+            // }
+
+            for url in &data.details.urls {
+                ui.hyperlink(url.expose());
+            }
+            if !data.details.tags.is_empty() {
+                ui.label(format!("Tags: {}", entry::join_tags(&data.details.tags).expose()));
+            }
+            // Hidden custom fields are only drawn once their eye button is clicked
+            for (index, field) in data.details.custom_fields.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(format!("{}: ", field.name.expose()));
+                    if field.hidden {
+                        secret_label(ui, ("custom field", entry_id, index), &field.value, self.reveal_timeout);
+                    } else {
+                        ui.label(field.value.expose());
+                    }
+                    if ui.button("Copy").clicked() {
+                        copy = Some(field.value.clone());
+                    }
+                });
+            }
+            if !data.details.notes.expose().is_empty() {
+                ui.separator();
+                ui.label(data.details.notes.expose());
+            }
+
+            ui.separator();
+            ui.label("Attachments: ");
+            for attachment in &self.attachments {
+                ui.horizontal(|ui| {
+                    let Ok(metadata) = &attachment.metadata else {
+                        ui.label(RichText::new("Corrupted attachment, it could not be decrypted").color(Color32::RED));
+                        return;
+                    };
+                    ui.label(format!("{} ({}, added {})", metadata.filename.expose(), format_size(attachment.size), format_age(Some(attachment.created_at))));
+                    if ui.button("Export").on_hover_text("Saved to your downloads folder").clicked() {
+                        attachment_action = Some(AttachmentAction::Export(attachment.id));
+                    }
+                    // Attachments don't go to the trash, so deleting one is confirmed first
+                    if self.confirm_remove_attachment == Some(attachment.id) {
+                        ui.label("Delete it for good?");
+                        if ui.button("Yes").clicked() {
+                            attachment_action = Some(AttachmentAction::Remove(attachment.id));
+                        }
+                        if ui.button("No").clicked() {
+                            attachment_action = Some(AttachmentAction::CancelRemove);
+                        }
+                    } else if ui.button("Delete").clicked() {
+                        attachment_action = Some(AttachmentAction::ConfirmRemove(attachment.id));
+                    }
+                });
+            }
+            ui.horizontal(|ui| {
+                ui.add(egui::TextEdit::singleline(&mut self.attachment_path).hint_text("Path of a file, or drop one on the window"));
+                if ui.button("Attach").clicked() && !self.attachment_path.trim().is_empty() {
+                    attachment_action = Some(AttachmentAction::Attach(self.attachment_path.trim().into()));
+                }
+            });
+            if !self.attachment_message.is_empty() {
+                ui.label(&self.attachment_message);
+            }
+
+            ui.separator();
+            ui.label(format!("Created {}", format_age(entry.created_at)));
+            ui.label(format!("Modified {}", format_age(entry.modified_at)));
+            ui.label(format!("Last used {}", format_age(entry.last_used_at)));
+        });
+
+        if let Some(action) = attachment_action {
+            self.apply_attachment_action(action, entry_id);
+        }
+        if next_hotp {
+            // The counter is saved before the code is used, so the same code is never handed out twice
+            // It is bumped on a copy so the entry on screen only moves on once the new counter is in the database
+            let mut updated = self.current_entry.clone();
+            let otp = updated.details.otp.as_mut().expect("Entry has a two-factor secret");
+            let code = otp.code(0);
+            if let OtpCounter::Counter(counter) = &mut otp.counter {
+                *counter += 1;
+            }
+            let unlocked = self.unlocked.as_mut().expect("Vault is locked");
+            match unlocked.update(&self.vault, entry_id, &updated) {
+                Ok(()) => {
+                    self.current_entry = updated;
+                    copy = Some(code);
+                }
+                Err(error) => self.error_message = format!("The two-factor counter could not be saved: {}", error),
+            }
         }
-        
-        if ui.button("Copy to clipboard").clicked() {
-            let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-            ctx.set_contents(self.current_password.clone()).expect("Failed to copy to clipboard");
+        if read {
+            self.reading = Some(std::time::Instant::now());
+        }
+        if let Some(since) = self.reading {
+            let mut open = true;
+            egui::Window::new("Show for reading").open(&mut open).collapsible(false).resizable(false).show(ui.ctx(), |ui| {
+                reading_view(ui, &self.current_entry.password);
+            });
+            // It hides itself again like any revealed field
+            let timeout = std::time::Duration::from_secs(self.reveal_timeout.into());
+            if !open || (self.reveal_timeout > 0 && since.elapsed() >= timeout) {
+                self.reading = None;
+            } else if self.reveal_timeout > 0 {
+                ui.ctx().request_repaint_after(timeout - since.elapsed());
+            }
+        }
+        if let Some(value) = copy {
+            match self.clipboard.copy(value.expose(), self.clipboard_timeout) {
+                Ok(()) => {
+                    self.error_message.clear();
+                    self.unlocked.as_mut().expect("Vault is locked").mark_used(&self.vault, entry_id);
+                }
+                Err(error) => self.error_message = format!("Could not copy to the clipboard: {}", error),
+            }
+        }
+        if !self.error_message.is_empty() {
+            ui.label(RichText::new(&self.error_message).color(Color32::RED));
         }
-        // if ui.button("Check if password has been compromised").clicked() {
-            
-        // }
         if ui.button("Back").clicked() {
-            self.current_account.clear();
-            self.current_website.clear();
-            self.current_password.clear();
+            self.error_message.clear();
+            self.clear_current_entry();
             self.current_screen = Screen::Main;
         }
     }
+    /// Attach, export or delete a file of the entry being viewed
+    fn apply_attachment_action(&mut self, action: AttachmentAction, entry_id: i32) {
+        self.error_message.clear();
+        self.attachment_message.clear();
+        let unlocked = self.unlocked.as_mut().expect("Vault is locked");
+        match action {
+            AttachmentAction::Attach(path) => {
+                // Files that are too big are turned away before any of them is read
+                let result = std::fs::File::open(&path).and_then(|file| {
+                    if file.metadata()?.len() > storage_options_sql::MAX_ATTACHMENT_SIZE {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!(
+                            "attachments can be at most {} MB", storage_options_sql::MAX_ATTACHMENT_SIZE / 1024 / 1024
+                        )));
+                    }
+                    let filename = path.file_name().map_or("attachment".into(), |name| name.to_string_lossy());
+                    let metadata = AttachmentMetadata { mime: guess_mime(&filename).to_string(), filename: SecretString::from(filename.as_ref()) };
+                    unlocked.add_attachment(&self.vault, entry_id, &metadata, std::io::BufReader::new(file))
+                });
+                match result {
+                    Ok(attachment) => {
+                        self.attachments.push(attachment);
+                        self.attachment_path.clear();
+                    }
+                    Err(error) => self.error_message = format!("The file could not be attached: {}", error),
+                }
+            }
+            AttachmentAction::Export(attachment_id) => {
+                let attachment = self.attachments.iter().find(|attachment| attachment.id == attachment_id).expect("Attachment is listed");
+                let filename = attachment.metadata.as_ref().map_or("attachment", |metadata| metadata.filename.expose());
+                match export_path(filename) {
+                    Some(path) => {
+                        let result = std::fs::OpenOptions::new().write(true).create_new(true).open(&path)
+                            .and_then(|file| unlocked.export_attachment(&self.vault, attachment_id, std::io::BufWriter::new(file)));
+                        match result {
+                            Ok(_) => self.attachment_message = format!("Saved to {}", path.display()),
+                            Err(error) => {
+                                // A half written file is removed rather than left looking like the real thing
+                                let _ = std::fs::remove_file(&path);
+                                self.error_message = format!("The attachment could not be exported: {}", error);
+                            }
+                        }
+                    }
+                    None => self.error_message = "There is no downloads folder to export the attachment to".to_string(),
+                }
+            }
+            AttachmentAction::ConfirmRemove(attachment_id) => self.confirm_remove_attachment = Some(attachment_id),
+            AttachmentAction::CancelRemove => self.confirm_remove_attachment = None,
+            AttachmentAction::Remove(attachment_id) => {
+                self.confirm_remove_attachment = None;
+                match unlocked.remove_attachment(&self.vault, attachment_id) {
+                    Ok(()) => self.attachments.retain(|attachment| attachment.id != attachment_id),
+                    Err(error) => self.error_message = format!("The attachment could not be deleted: {}", error),
+                }
+            }
+        }
+    }
 }
 
 /// This is the implementation of the App trait for the PasswordManagerApp struct
 impl eframe::App for PasswordManagerApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        let screen = self.current_screen;
+        let was_unlocked = self.unlocked.is_some();
+        self.check_auto_lock(ctx);
         egui::CentralPanel::default().show(ctx, |ui| {
             let mut style = (*ctx.style()).clone();
             style.text_styles = [
@@ -399,97 +1273,441 @@ impl eframe::App for PasswordManagerApp {
                 Screen::Login => self.login_screen(ui),
                 Screen::InsertMaster => self.insert_master_screen(ui),
                 Screen::Main => self.main_screen(ui),
-                Screen::UserNotFound => self.user_not_found_screen(ui),
                 Screen::EnterNewMaster => self.enter_new_master_screen(ui),
                 Screen::AddPassword => self.add_password_screen(ui),
+                Screen::EditPassword => self.edit_password_screen(ui),
                 Screen::GetPassword => self.get_password_screen(ui),
+                Screen::Trash => self.trash_screen(ui),
+                Screen::Settings => self.settings_screen(ui),
                 Screen::ChangeMasterPassword => self.change_master_screen(ui)
             }
         });
+
+        // Revealed fields are hidden again once the user moves on, so nothing is still showing the next time a screen is opened
+        if self.current_screen != screen || (was_unlocked && self.unlocked.is_none()) {
+            ctx.data_mut(|data| data.remove_by_type::<Revealed>());
+        }
     }
     /// This function will be called when the application is exited, to clear all sensitive data from memory
     fn on_exit(&mut self, _ctx: Option<&eframe::glow::Context>) {
-        println!("Exiting");
-        self.hashed_master = [0; 32];
-        self.user_id = 0;
+        self.lock();
         self.account.clear();
-        self.current_account.clear();
-        self.current_website.clear();
-        self.current_password.clear();
+        self.clear_current_entry();
+        self.confirm_buffer.clear();
+    }
+
+}
+
+/// Format a number of seconds for display, like "2h 5m" or "45s"
+fn format_duration(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Format a file size for display, like "1.5 MB"
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=1048575 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+/// Guess the type of a file from its extension, it is stored along with the file's name
+fn guess_mime(filename: &str) -> &'static str {
+    let extension = filename.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase()).unwrap_or_default();
+    match extension.as_str() {
+        "pdf" => "application/pdf",
+        "txt" | "asc" => "text/plain",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "pem" | "key" | "crt" => "application/x-pem-file",
+        "zip" => "application/zip",
+        "json" => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Pick where to export an attachment, in the downloads folder without overwriting anything already there
+/// Only the last part of the stored name is used, so an attachment can never be written outside that folder
+fn export_path(filename: &str) -> Option<std::path::PathBuf> {
+    let directory = dirs::download_dir().or_else(dirs::home_dir)?;
+    let filename = std::path::Path::new(filename).file_name().map_or("attachment".into(), |name| name.to_string_lossy());
+    let (stem, extension) = match filename.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (filename.as_ref(), String::new()),
+    };
+
+    (0..100)
+        .map(|copy| if copy == 0 { directory.join(filename.as_ref()) } else { directory.join(format!("{} ({}){}", stem, copy, extension)) })
+        .find(|path| !path.exists())
+}
+
+/// Format when something happened, like "5m 10s ago", for timestamps that may not have been recorded
+fn format_age(timestamp: Option<i64>) -> String {
+    let Some(timestamp) = timestamp else {
+        return "unknown".to_string();
+    };
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |time| time.as_secs());
+    format!("{} ago", format_duration(now.saturating_sub(timestamp as u64)))
+}
+
+/// Show the fields of an entry for editing, shared by the add and edit screens
+/// Returns true if Enter was pressed in one of the single line fields
+/// Secrets like the password and the card number are masked until their eye button is clicked
+fn entry_form(ui: &mut egui::Ui, entry: &mut EntryData, tags: &mut SecretString, otp_uri: &mut SecretString, reveal_timeout: u32) -> bool {
+    let mut submitted = false;
+    let mut single_line = |ui: &mut egui::Ui, label: &str, text: &mut SecretString, secret: bool| {
+        ui.label(label);
+        let response = if secret { secret_edit(ui, label, text, reveal_timeout) } else { ui.text_edit_singleline(text) };
+        if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+            submitted = true;
+        }
+    };
+
+    let kind = entry.payload.kind();
+    single_line(ui, "Title: ", &mut entry.details.title, false);
+    if kind.has_account() {
+        single_line(ui, "Account name: ", &mut entry.account, false);
+        single_line(ui, "Website: ", &mut entry.website, false);
+    }
+    single_line(ui, &format!("{}: ", kind.password_label()), &mut entry.password, true);
+    if kind.has_chosen_password() && ui.button("Generate password").clicked() {
+        entry.password = SecretString::from(password_generator::generate_password(20));
+    }
+
+    match &mut entry.payload {
+        Payload::Login | Payload::SecureNote => {}
+        Payload::CreditCard(card) => {
+            single_line(ui, "Cardholder: ", &mut card.cardholder, false);
+            single_line(ui, "Card number: ", &mut card.number, true);
+            single_line(ui, "Expiry (MM/YY): ", &mut card.expiry, false);
+            single_line(ui, "CVV: ", &mut card.cvv, true);
+        }
+        Payload::Identity(identity) => {
+            single_line(ui, "Full name: ", &mut identity.full_name, false);
+            single_line(ui, "Email: ", &mut identity.email, false);
+            single_line(ui, "Phone: ", &mut identity.phone, false);
+            single_line(ui, "Address: ", &mut identity.address, false);
+            single_line(ui, "City: ", &mut identity.city, false);
+            single_line(ui, "State or region: ", &mut identity.region, false);
+            single_line(ui, "Postal code: ", &mut identity.postal_code, false);
+            single_line(ui, "Country: ", &mut identity.country, false);
+        }
+        Payload::SshKey(key) => {
+            let id = ui.make_persistent_id("private key");
+            let revealed = is_revealed(ui, id, reveal_timeout);
+            ui.horizontal(|ui| {
+                ui.label("Private key: ");
+                reveal_toggle(ui, id, revealed);
+            });
+            ui.add(egui::TextEdit::multiline(&mut key.private_key).code_editor().password(!revealed));
+            ui.label("Public key: ");
+            ui.add(egui::TextEdit::multiline(&mut key.public_key).code_editor());
+        }
+        Payload::WiFi(wifi) => {
+            single_line(ui, "Network name (SSID): ", &mut wifi.ssid, false);
+            egui::ComboBox::from_label("Security").selected_text(wifi.security.name()).show_ui(ui, |ui| {
+                for security in WifiSecurity::ALL {
+                    ui.selectable_value(&mut wifi.security, security, security.name());
+                }
+            });
+            ui.checkbox(&mut wifi.hidden_network, "Hidden network");
+        }
+        Payload::ApiToken(token) => {
+            single_line(ui, "Key id: ", &mut token.key_id, false);
+            single_line(ui, "Expires: ", &mut token.expires, false);
+        }
+    }
+
+    single_line(ui, "Two-factor secret (otpauth:// URI): ", otp_uri, true);
+    single_line(ui, "Tags (separated by commas): ", tags, false);
+    ui.checkbox(&mut entry.favorite, "Favorite");
+
+    ui.label("Other URLs: ");
+    let mut removed = None;
+    for (index, url) in entry.details.urls.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            ui.text_edit_singleline(url);
+            if ui.button("Remove").clicked() {
+                removed = Some(index);
+            }
+        });
+    }
+    if let Some(index) = removed {
+        entry.details.urls.remove(index);
+    }
+    if ui.button("Add URL").clicked() {
+        entry.details.urls.push(SecretString::new());
+    }
+
+    ui.label("Custom fields: ");
+    let mut removed = None;
+    for (index, field) in entry.details.custom_fields.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(&mut field.name).hint_text("Name").desired_width(120.0));
+            let id = ui.make_persistent_id(("custom field", index));
+            let revealed = is_revealed(ui, id, reveal_timeout);
+            ui.add(egui::TextEdit::singleline(&mut field.value).hint_text("Value").password(field.hidden && !revealed));
+            if field.hidden {
+                reveal_toggle(ui, id, revealed);
+            }
+            ui.checkbox(&mut field.hidden, "Hidden");
+            if ui.button("Remove").clicked() {
+                removed = Some(index);
+            }
+        });
+    }
+    if let Some(index) = removed {
+        entry.details.custom_fields.remove(index);
+    }
+    if ui.button("Add custom field").clicked() {
+        entry.details.custom_fields.push(CustomField::default());
+    }
+
+    ui.label("Notes: ");
+    ui.text_edit_multiline(&mut entry.details.notes);
+
+    submitted
+}
+
+/// Show the folders inside a parent folder, and the folders inside those, each with how many entries it holds
+fn folder_tree(
+    ui: &mut egui::Ui,
+    folders: &[Folder],
+    parent_id: Option<i32>,
+    counts: &HashMap<i32, usize>,
+    selected: Option<i32>,
+    action: &mut Option<FolderAction>,
+) {
+    for folder in folders.iter().filter(|folder| folder.parent_id == parent_id) {
+        let name = folder.name.as_ref().map_or("Corrupted folder", |name| name.expose());
+        let label = format!("{} ({})", name, counts.get(&folder.id).copied().unwrap_or(0));
+
+        let row = |ui: &mut egui::Ui| {
+            let dragged = ui.dnd_drag_source(egui::Id::new(("drag folder", folder.id)), Dragged::Folder(folder.id), |ui| {
+                ui.selectable_label(selected == Some(folder.id), label)
+            });
+            if dragged.inner.clicked() {
+                *action = Some(FolderAction::Select(Some(folder.id)));
+            }
+            dragged.inner.context_menu(|ui| {
+                if ui.button("Rename").clicked() {
+                    *action = Some(FolderAction::StartRename(folder.id));
+                    ui.close_menu();
+                }
+                if folder.parent_id.is_some() && ui.button("Move to the top level").clicked() {
+                    *action = Some(FolderAction::MoveFolder(folder.id, None));
+                    ui.close_menu();
+                }
+                if ui.button("Delete folder").on_hover_text("What is in it moves up a level").clicked() {
+                    *action = Some(FolderAction::Remove(folder.id));
+                    ui.close_menu();
+                }
+            });
+            folder_drop_target(ui, &dragged.response, Some(folder.id), action);
+        };
+
+        if folders.iter().any(|child| child.parent_id == Some(folder.id)) {
+            let id = ui.make_persistent_id(("folder", folder.id));
+            egui::collapsing_header::CollapsingState::load_with_default_open(ui.ctx(), id, true)
+                .show_header(ui, row)
+                .body(|ui| folder_tree(ui, folders, Some(folder.id), counts, selected, action));
+        } else {
+            ui.horizontal(|ui| {
+                // Lines up with the folders that have an arrow to open them
+                ui.add_space(ui.spacing().icon_width + ui.spacing().item_spacing.x);
+                row(ui);
+            });
+        }
+    }
+}
+
+/// Let entries and folders be dropped on a row of the folder sidebar, None being the top level
+fn folder_drop_target(ui: &egui::Ui, response: &egui::Response, folder_id: Option<i32>, action: &mut Option<FolderAction>) {
+    if response.dnd_hover_payload::<Dragged>().is_some() {
+        ui.painter().rect_stroke(response.rect, 2.0, ui.visuals().selection.stroke);
+    }
+    match response.dnd_release_payload::<Dragged>().as_deref() {
+        Some(&Dragged::Entry(entry_id)) => *action = Some(FolderAction::MoveEntry(entry_id, folder_id)),
+        // Dropping a folder on itself is just a click that moved a bit
+        Some(&Dragged::Folder(dropped_id)) if Some(dropped_id) != folder_id => *action = Some(FolderAction::MoveFolder(dropped_id, folder_id)),
+        _ => {}
+    }
+}
+
+/// When a masked field was revealed, kept in egui's memory under the id of the field
+#[derive(Clone, Copy)]
+struct Revealed(std::time::Instant);
+
+/// Check whether a masked field is revealed, hiding it again once it has been shown for longer than the timeout, 0 never hides it
+fn is_revealed(ui: &egui::Ui, id: egui::Id, timeout: u32) -> bool {
+    let Some(Revealed(since)) = ui.data(|data| data.get_temp::<Revealed>(id)) else {
+        return false;
+    };
+    if timeout == 0 {
+        return true;
+    }
+    match std::time::Duration::from_secs(timeout.into()).checked_sub(since.elapsed()) {
+        Some(remaining) if !remaining.is_zero() => {
+            ui.ctx().request_repaint_after(remaining);
+            true
+        }
+        _ => {
+            ui.data_mut(|data| data.remove::<Revealed>(id));
+            false
+        }
+    }
+}
+
+/// The eye button next to a masked field, which shows or hides what is in it
+fn reveal_toggle(ui: &mut egui::Ui, id: egui::Id, revealed: bool) {
+    let button = ui.selectable_label(revealed, "👁").on_hover_text(if revealed { "Hide" } else { "Show" });
+    if button.clicked() {
+        ui.data_mut(|data| if revealed {
+            data.remove::<Revealed>(id);
+        } else {
+            data.insert_temp(id, Revealed(std::time::Instant::now()));
+        });
     }
+}
+
+/// A text field that masks what is typed until its eye button is clicked
+fn secret_edit(ui: &mut egui::Ui, id_salt: impl std::hash::Hash, text: &mut SecretString, reveal_timeout: u32) -> egui::Response {
+    let id = ui.make_persistent_id(id_salt);
+    let revealed = is_revealed(ui, id, reveal_timeout);
+    ui.horizontal(|ui| {
+        let response = ui.add(egui::TextEdit::singleline(text).password(!revealed));
+        reveal_toggle(ui, id, revealed);
+        response
+    }).inner
+}
 
+/// Show a secret as stars until its eye button is clicked
+fn secret_label(ui: &mut egui::Ui, id_salt: impl std::hash::Hash, value: &SecretString, reveal_timeout: u32) {
+    let id = ui.make_persistent_id(id_salt);
+    let revealed = is_revealed(ui, id, reveal_timeout);
+    ui.label(if revealed { value.expose() } else { "********" });
+    reveal_toggle(ui, id, revealed);
+}
+
+/// Show a password in big characters, numbered and colored by type, so it can be read off the screen and typed somewhere else
+/// Digits and symbols stand out from letters, so look-alikes like O and 0 or l and | aren't mixed up
+fn reading_view(ui: &mut egui::Ui, password: &SecretString) {
+    const PER_ROW: usize = 8;
+    let length = password.expose().chars().count();
+    egui::Grid::new("reading").spacing([14.0, 2.0]).show(ui, |ui| {
+        for start in (0..length).step_by(PER_ROW) {
+            for character in password.expose().chars().skip(start).take(PER_ROW) {
+                let color = if character.is_ascii_digit() {
+                    Color32::LIGHT_BLUE
+                } else if character.is_alphabetic() {
+                    Color32::WHITE
+                } else {
+                    Color32::from_rgb(255, 190, 90)
+                };
+                // Spaces would be invisible on their own
+                let shown = if character == ' ' { '␣' } else { character };
+                ui.label(RichText::new(shown.to_string()).size(48.0).family(egui::FontFamily::Monospace).color(color));
+            }
+            ui.end_row();
+            for position in start..(start + PER_ROW).min(length) {
+                ui.label(RichText::new((position + 1).to_string()).size(12.5));
+            }
+            ui.end_row();
+        }
+    });
+}
+
+/// Show the fields only some kinds of entry have, secrets like card numbers are never drawn, only copied
+fn payload_view(ui: &mut egui::Ui, payload: &Payload, copy: &mut Option<SecretString>, reveal_timeout: u32) {
+    let mut row = |ui: &mut egui::Ui, label: &str, value: &SecretString, secret: bool| {
+        if value.expose().is_empty() {
+            return;
+        }
+        ui.horizontal(|ui| {
+            ui.label(format!("{}: ", label));
+            if secret {
+                secret_label(ui, label, value, reveal_timeout);
+            } else {
+                ui.label(value.expose());
+            }
+            if ui.button("Copy").clicked() {
+                *copy = Some(value.clone());
+            }
+        });
+    };
+
+    match payload {
+        Payload::Login | Payload::SecureNote => {}
+        Payload::CreditCard(card) => {
+            row(ui, "Cardholder", &card.cardholder, false);
+            row(ui, "Card number", &card.number, true);
+            row(ui, "Expiry", &card.expiry, false);
+            row(ui, "CVV", &card.cvv, true);
+        }
+        Payload::Identity(identity) => {
+            row(ui, "Full name", &identity.full_name, false);
+            row(ui, "Email", &identity.email, false);
+            row(ui, "Phone", &identity.phone, false);
+            row(ui, "Address", &identity.address, false);
+            row(ui, "City", &identity.city, false);
+            row(ui, "State or region", &identity.region, false);
+            row(ui, "Postal code", &identity.postal_code, false);
+            row(ui, "Country", &identity.country, false);
+        }
+        Payload::SshKey(key) => {
+            row(ui, "Private key", &key.private_key, true);
+            row(ui, "Public key", &key.public_key, false);
+        }
+        Payload::WiFi(wifi) => {
+            row(ui, "Network name", &wifi.ssid, false);
+            ui.label(format!("Security: {}", wifi.security.name()));
+            if wifi.hidden_network {
+                ui.label("Hidden network");
+            }
+        }
+        Payload::ApiToken(token) => {
+            row(ui, "Key id", &token.key_id, false);
+            row(ui, "Expires", &token.expires, false);
+        }
+    }
 }
 
 // This is the main function that will run the application by running native egui
 fn main() {
-    std::fs::create_dir_all("storage").expect("Failed to create storage directory");
-    init_sql_storage();
-    init_user_id_table();
+    let vault_path = vault_path_from_args(std::env::args().skip(1)).unwrap_or_else(storage_options_sql::default_vault_path);
+    // A database the app can't safely open is reported instead of being touched
+    let vault = match Vault::open(&vault_path) {
+        Ok(vault) => vault,
+        Err(error) => {
+            eprintln!("Failed to open the vault at {}: {}", vault_path.display(), error);
+            std::process::exit(1);
+        }
+    };
     let options = eframe::NativeOptions::default();
     eframe::run_native(
         "Password Manager App",
         options,
-        Box::new(|_cc| Ok(Box::new(PasswordManagerApp::new()))),
+        Box::new(|_cc| Ok(Box::new(PasswordManagerApp::new(vault)))),
     ).expect("Failed to run native");
 }
 
-/// This function initializes the user_id table in the SQL database
-fn init_user_id_table() {
-    // This is the initialization of the storage database with the encrypted passwords in SQL using SQLx
-    let conn = rusqlite::Connection::open("storage/passwords.db").unwrap();
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS user_id (
-            user_id INTEGER PRIMARY KEY,
-            account BLOB NOT NULL,
-            hashed_master_password BLOB NOT NULL,
-            salt BLOB,
-            kdf_salt BLOB
-        )",
-        [],
-    ).expect("Failed to create SQL user_id table");
-
-    // Now we add an admin account if there is not already one, there must always be an admin
-    let mut statement = conn.prepare("SELECT COUNT(*) FROM user_id WHERE account = 'admin'").expect("Failed to prepare statement");
-    let count: i32 = statement.query_row([], |row| row.get(0)).expect("Failed to get count of admin account");
-
-    if count == 0 {
-        let hashed_master = encryption_algorithms::hash_master("admin", [0; 32]);
-        let hashed_master_vec = hashed_master.to_vec();
-        conn.execute(
-            "INSERT INTO user_id (user_id, account, hashed_master_password) VALUES (0, 'admin', ?)",
-            rusqlite::params![hashed_master_vec]
-        ).expect("Failed to add admin account");
+/// Find the vault given with --vault <path> or --vault=<path> on the command line, if any
+fn vault_path_from_args(mut args: impl Iterator<Item = String>) -> Option<std::path::PathBuf> {
+    while let Some(arg) = args.next() {
+        if arg == "--vault" {
+            return args.next().map(std::path::PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--vault=") {
+            return Some(std::path::PathBuf::from(path));
+        }
     }
+    None
 }
-
-/// This function initializes the password table in the SQL database
-fn init_sql_storage() {
-    // This is the initialization of the storage database with the encrypted passwords in SQL using SQLx
-    let conn = Connection::open("storage/passwords.db").unwrap();
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS passwords (
-            entry_id INTEGER PRIMARY KEY,
-            user_id INTEGER NOT NULL,
-            account BLOB NOT NULL,
-            password BLOB NOT NULL,
-            website BLOB NOT NULL
-        )",
-        [],
-    ).expect("Failed to create SQL password table");
-
-    // Now we add an initial password for the admin account
-    let mut statement = conn.prepare("SELECT COUNT(*) FROM passwords WHERE account = 'admin'").expect("Failed to prepare statement");
-    let count: i32 = statement.query_row([], |row| row.get(0)).expect("Failed to get count of admin account");
-
-    if count == 0 {
-        let hashed_master = encryption_algorithms::hash_master("admin", [0; 32]);
-        // Admin is a misnomer, since this doesn't really have any permissions, it is just the initial created account to avoid 
-        // Tricky edge cases in creation of the SQL tables
-        let hashed_master_vec = hashed_master.to_vec();
-        conn.execute(
-            "INSERT INTO passwords (user_id, account, password, website) VALUES (0, 'admin', ?, 'admin')",
-            rusqlite::params![hashed_master_vec]
-        ).expect("Failed to add admin account");
-    }
-
-}
\ No newline at end of file
@@ -0,0 +1,325 @@
+use crate::secret_memory::SecretString;
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha1::Sha1;
+use crypto::sha2::{Sha256, Sha512};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+/// The hash the one-time codes are made with, almost every site uses SHA1
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OtpAlgorithm {
+    #[default]
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl OtpAlgorithm {
+    /// The name used in otpauth:// URIs
+    pub fn name(self) -> &'static str {
+        match self {
+            OtpAlgorithm::Sha1 => "SHA1",
+            OtpAlgorithm::Sha256 => "SHA256",
+            OtpAlgorithm::Sha512 => "SHA512",
+        }
+    }
+}
+
+/// What the codes are counted by, the time for TOTP (RFC 6238) or a counter that goes up with every code for HOTP (RFC 4226)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OtpCounter {
+    /// A new code every period seconds
+    Time { period: u64 },
+    /// The counter of the next code
+    Counter(u64),
+}
+
+/// Everything that can be wrong with an otpauth:// URI
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OtpError {
+    NotOtpAuth,
+    UnknownType,
+    MissingSecret,
+    InvalidSecret,
+    UnsupportedAlgorithm,
+    InvalidDigits,
+    InvalidPeriod,
+    MissingCounter,
+}
+
+impl std::fmt::Display for OtpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            OtpError::NotOtpAuth => "the two-factor secret should be an otpauth:// URI",
+            OtpError::UnknownType => "only totp and hotp two-factor codes are supported",
+            OtpError::MissingSecret => "the two-factor URI has no secret",
+            OtpError::InvalidSecret => "the two-factor secret is not valid base32",
+            OtpError::UnsupportedAlgorithm => "the two-factor algorithm should be SHA1, SHA256 or SHA512",
+            OtpError::InvalidDigits => "two-factor codes should have 6 to 8 digits",
+            OtpError::InvalidPeriod => "the two-factor period should be a positive number of seconds",
+            OtpError::MissingCounter => "hotp two-factor URIs need a counter",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+/// A two-factor secret along with how its codes are made, kept in the encrypted details of an entry
+/// Stored parameters are checked like a parsed URI when they are read back, so a bad period or digit count never reaches code
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedOtpParams")]
+pub struct OtpParams {
+    /// The shared secret, in base32 as it was in the URI
+    pub secret: SecretString,
+    pub algorithm: OtpAlgorithm,
+    pub digits: u32,
+    pub counter: OtpCounter,
+    pub issuer: SecretString,
+    pub account: SecretString,
+}
+
+/// OtpParams as they were stored, before they are checked
+#[derive(Deserialize)]
+struct UncheckedOtpParams {
+    secret: SecretString,
+    algorithm: OtpAlgorithm,
+    digits: u32,
+    counter: OtpCounter,
+    issuer: SecretString,
+    account: SecretString,
+}
+
+impl TryFrom<UncheckedOtpParams> for OtpParams {
+    type Error = OtpError;
+
+    fn try_from(params: UncheckedOtpParams) -> Result<Self, OtpError> {
+        let UncheckedOtpParams { secret, algorithm, digits, counter, issuer, account } = params;
+        Self { secret, algorithm, digits, counter, issuer, account }.checked()
+    }
+}
+
+impl OtpParams {
+    /// Parse an otpauth:// URI, as found in the QR codes sites show when two-factor authentication is turned on
+    /// Anything left out gets the defaults authenticator apps use, SHA1, 6 digits and 30 seconds
+    pub fn from_uri(uri: &str) -> Result<Self, OtpError> {
+        let rest = uri.trim().strip_prefix("otpauth://").ok_or(OtpError::NotOtpAuth)?;
+        let (otp_type, rest) = rest.split_once('/').ok_or(OtpError::NotOtpAuth)?;
+        let (label, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        // The label is "Issuer:account" or just the account
+        let label = Zeroizing::new(percent_decode_str(label).decode_utf8_lossy().into_owned());
+        let (mut issuer, account) = match label.split_once(':') {
+            Some((issuer, account)) => (SecretString::from(issuer.trim()), SecretString::from(account.trim())),
+            None => (SecretString::new(), SecretString::from(label.trim())),
+        };
+
+        let mut secret = None;
+        let mut algorithm = OtpAlgorithm::Sha1;
+        let mut digits = 6;
+        let mut period = 30;
+        let mut counter = None;
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = Zeroizing::new(percent_decode_str(value).decode_utf8_lossy().into_owned());
+            match key.to_ascii_lowercase().as_str() {
+                "secret" => secret = Some(SecretString::from(value.replace(' ', "").to_ascii_uppercase())),
+                "issuer" => issuer = SecretString::from(value.as_str()),
+                "algorithm" => {
+                    algorithm = match value.to_ascii_uppercase().as_str() {
+                        "SHA1" => OtpAlgorithm::Sha1,
+                        "SHA256" => OtpAlgorithm::Sha256,
+                        "SHA512" => OtpAlgorithm::Sha512,
+                        _ => return Err(OtpError::UnsupportedAlgorithm),
+                    }
+                }
+                "digits" => digits = value.parse().map_err(|_| OtpError::InvalidDigits)?,
+                "period" => period = value.parse().map_err(|_| OtpError::InvalidPeriod)?,
+                "counter" => counter = Some(value.parse().map_err(|_| OtpError::MissingCounter)?),
+                // Apps add their own parameters, like an image, which don't change the codes
+                _ => {}
+            }
+        }
+
+        let secret = secret.ok_or(OtpError::MissingSecret)?;
+        let counter = match otp_type.to_ascii_lowercase().as_str() {
+            "totp" => OtpCounter::Time { period },
+            "hotp" => OtpCounter::Counter(counter.ok_or(OtpError::MissingCounter)?),
+            _ => return Err(OtpError::UnknownType),
+        };
+
+        Self { secret, algorithm, digits, counter, issuer, account }.checked()
+    }
+
+    /// Check everything the codes are made from, for parsed URIs and stored parameters alike
+    fn checked(self) -> Result<Self, OtpError> {
+        if self.secret.expose().is_empty() {
+            return Err(OtpError::MissingSecret);
+        }
+        base32_decode(self.secret.expose()).ok_or(OtpError::InvalidSecret)?;
+        if !(6..=8).contains(&self.digits) {
+            return Err(OtpError::InvalidDigits);
+        }
+        if self.counter == (OtpCounter::Time { period: 0 }) {
+            return Err(OtpError::InvalidPeriod);
+        }
+
+        Ok(self)
+    }
+
+    /// Write the parameters back out as an otpauth:// URI, so they can be edited or moved to another app
+    pub fn to_uri(&self) -> SecretString {
+        let encode = |text: &SecretString| utf8_percent_encode(text.expose(), NON_ALPHANUMERIC).to_string();
+        let (otp_type, counter) = match self.counter {
+            OtpCounter::Time { period } => ("totp", format!("period={}", period)),
+            OtpCounter::Counter(counter) => ("hotp", format!("counter={}", counter)),
+        };
+        let label = if self.issuer.expose().is_empty() {
+            encode(&self.account)
+        } else {
+            format!("{}:{}", encode(&self.issuer), encode(&self.account))
+        };
+
+        SecretString::from(format!(
+            "otpauth://{}/{}?secret={}&issuer={}&algorithm={}&digits={}&{}",
+            otp_type, label, self.secret.expose(), encode(&self.issuer), self.algorithm.name(), self.digits, counter
+        ))
+    }
+
+    /// Get the code for a moment in seconds since the unix epoch, or the code for the current counter of HOTP secrets
+    pub fn code(&self, unix_time: u64) -> SecretString {
+        let counter = match self.counter {
+            OtpCounter::Time { period } => unix_time / period,
+            OtpCounter::Counter(counter) => counter,
+        };
+        let key = base32_decode(self.secret.expose()).expect("The secret was checked when it was parsed");
+        SecretString::from(format!("{:0width$}", hotp(&key, counter, self.digits, self.algorithm), width = self.digits as usize))
+    }
+
+    /// How many seconds the current TOTP code has left, None for HOTP secrets
+    pub fn seconds_remaining(&self, unix_time: u64) -> Option<u64> {
+        match self.counter {
+            OtpCounter::Time { period } => Some(period - unix_time % period),
+            OtpCounter::Counter(_) => None,
+        }
+    }
+}
+
+/// Work out a one-time code from a key and a counter, as described in RFC 4226
+pub fn hotp(key: &[u8], counter: u64, digits: u32, algorithm: OtpAlgorithm) -> u32 {
+    let hash = match algorithm {
+        OtpAlgorithm::Sha1 => hmac(Sha1::new(), key, &counter.to_be_bytes()),
+        OtpAlgorithm::Sha256 => hmac(Sha256::new(), key, &counter.to_be_bytes()),
+        OtpAlgorithm::Sha512 => hmac(Sha512::new(), key, &counter.to_be_bytes()),
+    };
+
+    // The last nibble picks which 4 bytes of the hash become the code
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]) & 0x7fff_ffff;
+    truncated % 10u32.pow(digits)
+}
+
+fn hmac(digest: impl Digest, key: &[u8], message: &[u8]) -> Zeroizing<Vec<u8>> {
+    let mut mac = Hmac::new(digest, key);
+    mac.input(message);
+    Zeroizing::new(mac.result().code().to_vec())
+}
+
+/// Decode base32 as used for two-factor secrets, ignoring case and padding, None if it isn't valid base32
+fn base32_decode(text: &str) -> Option<Zeroizing<Vec<u8>>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut bytes = Zeroizing::new(Vec::new());
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let value = ALPHABET.iter().position(|&letter| letter == c.to_ascii_uppercase())?;
+        // Only the bits that haven't been written out yet are kept
+        buffer = ((buffer << 5) | value as u32) & 0xfff;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    (!bytes.is_empty()).then_some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hotp_rfc4226() {
+        // The test values from RFC 4226 appendix D
+        let codes = [755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489];
+        for (counter, code) in codes.into_iter().enumerate() {
+            assert_eq!(hotp(b"12345678901234567890", counter as u64, 6, OtpAlgorithm::Sha1), code);
+        }
+    }
+
+    #[test]
+    fn test_totp_rfc6238() {
+        // The test values from RFC 6238 appendix B, each algorithm has a seed of its own length
+        let seeds = [
+            (OtpAlgorithm::Sha1, &b"12345678901234567890"[..]),
+            (OtpAlgorithm::Sha256, &b"12345678901234567890123456789012"[..]),
+            (OtpAlgorithm::Sha512, &b"1234567890123456789012345678901234567890123456789012345678901234"[..]),
+        ];
+        let codes = [
+            (59, [94287082, 46119246, 90693936]),
+            (1111111109, [7081804, 68084774, 25091201]),
+            (1111111111, [14050471, 67062674, 99943326]),
+            (1234567890, [89005924, 91819424, 93441116]),
+            (2000000000, [69279037, 90698825, 38618901]),
+            (20000000000, [65353130, 77737706, 47863826]),
+        ];
+        for (time, expected) in codes {
+            for ((algorithm, seed), code) in seeds.iter().zip(expected) {
+                assert_eq!(hotp(seed, time / 30, 8, *algorithm), code, "{:?} at {}", algorithm, time);
+            }
+        }
+    }
+
+    #[test]
+    fn test_otpauth_uri() {
+        let params = OtpParams::from_uri("otpauth://totp/ACME%20Co:john@example.com?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&digits=8").unwrap();
+        assert_eq!(params.issuer.expose(), "ACME Co");
+        assert_eq!(params.account.expose(), "john@example.com");
+        assert_eq!((params.algorithm, params.digits, params.counter), (OtpAlgorithm::Sha1, 8, OtpCounter::Time { period: 30 }));
+        // Leading zeros are kept
+        assert_eq!(params.code(1111111109).expose(), "07081804");
+        assert_eq!(params.seconds_remaining(1111111109), Some(1));
+
+        // Writing the URI back out and parsing it again gives the same secret
+        assert_eq!(OtpParams::from_uri(params.to_uri().expose()).unwrap(), params);
+
+        let hotp = OtpParams::from_uri("otpauth://hotp/alice?secret=gezdgnbvgy3tqojqgezdgnbvgy3tqojq&counter=1&algorithm=SHA1").unwrap();
+        assert_eq!((hotp.code(0).expose(), hotp.seconds_remaining(0)), ("287082", None));
+        let custom = OtpParams::from_uri("otpauth://totp/x?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&period=60&algorithm=SHA256").unwrap();
+        assert_eq!(custom.counter, OtpCounter::Time { period: 60 });
+
+        assert_eq!(OtpParams::from_uri("https://example.com"), Err(OtpError::NotOtpAuth));
+        assert_eq!(OtpParams::from_uri("otpauth://totp/x?digits=6"), Err(OtpError::MissingSecret));
+        assert_eq!(OtpParams::from_uri("otpauth://totp/x?secret=not-base32"), Err(OtpError::InvalidSecret));
+        assert_eq!(OtpParams::from_uri("otpauth://totp/x?secret=GEZDGNBV&digits=9"), Err(OtpError::InvalidDigits));
+        assert_eq!(OtpParams::from_uri("otpauth://totp/x?secret=GEZDGNBV&algorithm=MD5"), Err(OtpError::UnsupportedAlgorithm));
+        assert_eq!(OtpParams::from_uri("otpauth://totp/x?secret=GEZDGNBV&period=0"), Err(OtpError::InvalidPeriod));
+        assert_eq!(OtpParams::from_uri("otpauth://hotp/x?secret=GEZDGNBV"), Err(OtpError::MissingCounter));
+        assert_eq!(OtpParams::from_uri("otpauth://motp/x?secret=GEZDGNBV"), Err(OtpError::UnknownType));
+    }
+
+    #[test]
+    fn test_stored_params_are_checked() {
+        let params = OtpParams::from_uri("otpauth://totp/x?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap();
+        let stored = serde_json::to_string(&params).unwrap();
+        assert_eq!(serde_json::from_str::<OtpParams>(&stored).unwrap(), params);
+
+        // Parameters that would make code panic are refused when they are read back
+        let zero_period = stored.replace("\"period\":30", "\"period\":0");
+        assert!(serde_json::from_str::<OtpParams>(&zero_period).is_err());
+        let too_many_digits = stored.replace("\"digits\":6", "\"digits\":10");
+        assert!(serde_json::from_str::<OtpParams>(&too_many_digits).is_err());
+    }
+}
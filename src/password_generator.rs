@@ -1,5 +1,7 @@
 use rand::{rngs::StdRng, Rng, SeedableRng};
-use std::fs::File;
+
+/// The list of the 100k most common passwords, built into the binary so it works from any working directory
+const COMMON_PASSWORDS: &str = include_str!("../storage/100k-most-used-passwords-NCSC.txt");
 
 /// This function generates a password of a given length
 pub fn generate_password(length: u32) -> String {
@@ -31,10 +33,7 @@ pub fn check_password_safety(password: &str) -> (u32, Vec<String>) {
     let mut safety_message: Vec<String>= Vec::new();
 
     // Check if the password is on a common password list from the storage/100k-most-used-passwords.txt file
-    use std::io::{BufRead, BufReader};
-    
-    let common_passwords = BufReader::new(File::open("storage/100k-most-used-passwords-NCSC.txt").expect("Failed to open common passwords file"));
-    if common_passwords.lines().any(|line| line.unwrap() == password) {
+    if COMMON_PASSWORDS.lines().any(|line| line == password) {
         safety_message.push("This password is on a common password list".to_string());
         return (0, safety_message);
     } else {
@@ -93,6 +92,25 @@ pub fn check_password_safety(password: &str) -> (u32, Vec<String>) {
     (safety_rating, safety_message)
 }
 
+/// Check a new master password and its confirmation before an account is created or its master password is changed
+/// Returns what is wrong with it, if anything, for the user to fix
+pub fn check_new_master_password(password: &str, confirmation: &str, minimum_score: u32) -> Result<(), String> {
+    if password.is_empty() {
+        return Err("Please enter a master password".to_string());
+    }
+    if password != confirmation {
+        return Err("The master passwords don't match".to_string());
+    }
+    let (safety_rating, _) = check_password_safety(password);
+    if safety_rating < minimum_score {
+        return Err(format!(
+            "This master password is too weak, it is rated {}/50 and this vault needs at least {}/50",
+            safety_rating, minimum_score
+        ));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -119,6 +137,17 @@ mod tests {
         assert_eq!(safety_message, empty_vec);
     }
 
+    #[test]
+    fn test_check_new_master_password() {
+        assert_eq!(check_new_master_password("", "", 0), Err("Please enter a master password".to_string()));
+        assert_eq!(check_new_master_password("[bX+L+$8x4T7", "[bX+L+$8x4T", 30), Err("The master passwords don't match".to_string()));
+        assert!(check_new_master_password("password", "password", 30).unwrap_err().contains("too weak"));
+        assert_eq!(check_new_master_password("[bX+L+$8x4T7", "[bX+L+$8x4T7", 50), Ok(()));
+
+        // Vaults can lower the bar, but never below a password being entered at all
+        assert_eq!(check_new_master_password("password", "password", 0), Ok(()));
+    }
+
     #[test]
     fn test_good_password() {
         let password = "[bX+L+$8x4T7";
@@ -0,0 +1,302 @@
+use std::fmt;
+use std::ops::Range;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+/// Lock the pages behind a buffer into RAM so they are never written to swap
+/// This is best effort, if the OS refuses (for example because of RLIMIT_MEMLOCK) the secret is still wiped on drop
+fn lock_memory(ptr: *const u8, len: usize) {
+    if len == 0 {
+        return;
+    }
+    #[cfg(unix)]
+    unsafe {
+        libc::mlock(ptr as *const libc::c_void, len);
+    }
+    #[cfg(windows)]
+    unsafe {
+        winapi::um::memoryapi::VirtualLock(ptr as *mut winapi::ctypes::c_void, len);
+    }
+}
+
+/// Unlock pages that were locked with lock_memory
+/// Locks aren't counted, so this also unlocks any other secret sharing the same page, which is why it is only done on drop
+fn unlock_memory(ptr: *const u8, len: usize) {
+    if len == 0 {
+        return;
+    }
+    #[cfg(unix)]
+    unsafe {
+        libc::munlock(ptr as *const libc::c_void, len);
+    }
+    #[cfg(windows)]
+    unsafe {
+        winapi::um::memoryapi::VirtualUnlock(ptr as *mut winapi::ctypes::c_void, len);
+    }
+}
+
+/// A 256 bit key that lives in locked memory and is wiped when dropped
+/// Keys are written in place through expose_mut, so they never sit in a plain array on the stack
+pub struct SecretKey {
+    bytes: Box<[u8; 32]>,
+}
+
+impl SecretKey {
+    /// Create an all zero key to be filled in with expose_mut
+    pub fn zeroed() -> Self {
+        let bytes = Box::new([0u8; 32]);
+        lock_memory(bytes.as_ptr(), bytes.len());
+        Self { bytes }
+    }
+
+    /// Copy a key out of a buffer, the caller is responsible for wiping the buffer
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 32 {
+            return None;
+        }
+        let mut key = Self::zeroed();
+        key.bytes.copy_from_slice(bytes);
+        Some(key)
+    }
+
+    /// Borrow the key bytes
+    pub fn expose(&self) -> &[u8; 32] {
+        &self.bytes
+    }
+
+    /// Mutably borrow the key bytes, used to derive or generate a key directly into locked memory
+    pub fn expose_mut(&mut self) -> &mut [u8; 32] {
+        &mut self.bytes
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+        unlock_memory(self.bytes.as_ptr(), self.bytes.len());
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretKey([REDACTED])")
+    }
+}
+
+/// Keys are compared in constant time so the comparison doesn't leak how many leading bytes matched
+impl PartialEq for SecretKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes.ct_eq(&*other.bytes).into()
+    }
+}
+
+/// A string holding a password or other decrypted data
+/// The buffer is locked into memory and wiped when dropped, and when it has to grow the old buffer is wiped
+/// before being freed, so typing into it doesn't leave partial copies all over the heap
+pub struct SecretString {
+    text: String,
+}
+
+/// Start with enough room for most passwords so the buffer rarely has to move
+const INITIAL_CAPACITY: usize = 64;
+
+impl SecretString {
+    /// Create an empty secret string
+    pub fn new() -> Self {
+        Self::with_capacity(INITIAL_CAPACITY)
+    }
+
+    fn with_capacity(capacity: usize) -> Self {
+        let text = String::with_capacity(capacity);
+        lock_memory(text.as_ptr(), text.capacity());
+        Self { text }
+    }
+
+    /// Borrow the plaintext
+    pub fn expose(&self) -> &str {
+        &self.text
+    }
+
+    /// Wipe the contents, keeping the buffer for reuse
+    pub fn clear(&mut self) {
+        // Zeroizing a String wipes the whole capacity and sets the length to 0
+        self.text.zeroize();
+    }
+
+    /// Append text, moving to a bigger locked buffer first if needed
+    pub fn push_str(&mut self, text: &str) {
+        self.reserve(text.len());
+        self.text.push_str(text);
+    }
+
+    /// Make sure there is room for additional bytes without String reallocating behind our back
+    fn reserve(&mut self, additional: usize) {
+        let needed = self.text.len() + additional;
+        if needed <= self.text.capacity() {
+            return;
+        }
+
+        let mut grown = Self::with_capacity(needed.max(self.text.capacity() * 2));
+        grown.text.push_str(&self.text);
+        // Dropping the old value through the swap wipes and unlocks the old buffer
+        std::mem::swap(self, &mut grown);
+    }
+}
+
+impl Default for SecretString {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(text: &str) -> Self {
+        let mut secret = Self::with_capacity(text.len().max(INITIAL_CAPACITY));
+        secret.text.push_str(text);
+        secret
+    }
+}
+
+impl From<String> for SecretString {
+    /// Take over an existing String's buffer without copying it
+    fn from(text: String) -> Self {
+        lock_memory(text.as_ptr(), text.capacity());
+        Self { text }
+    }
+}
+
+impl Clone for SecretString {
+    fn clone(&self) -> Self {
+        Self::from(self.expose())
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        let (ptr, capacity) = (self.text.as_ptr(), self.text.capacity());
+        self.text.zeroize();
+        unlock_memory(ptr, capacity);
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretString([REDACTED])")
+    }
+}
+
+impl PartialEq for SecretString {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+    }
+}
+
+/// Secrets are written out as plain JSON strings, only ever into buffers that are encrypted or wiped afterwards
+impl serde::Serialize for SecretString {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.expose())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for SecretString {
+    /// The deserialized String is taken over as is, so there is no second copy to wipe
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(SecretString::from)
+    }
+}
+
+/// Lets egui text fields edit a SecretString directly instead of going through a plain String
+/// egui still lays the text out for display, so masking the field is what keeps the glyphs out of its caches
+impl egui::TextBuffer for SecretString {
+    fn is_mutable(&self) -> bool {
+        true
+    }
+
+    fn as_str(&self) -> &str {
+        self.expose()
+    }
+
+    fn insert_text(&mut self, text: &str, char_index: usize) -> usize {
+        let byte_index = self.byte_index_from_char_index(char_index);
+        self.reserve(text.len());
+        self.text.insert_str(byte_index, text);
+
+        text.chars().count()
+    }
+
+    fn delete_char_range(&mut self, char_range: Range<usize>) {
+        let byte_start = self.byte_index_from_char_index(char_range.start);
+        let byte_end = self.byte_index_from_char_index(char_range.end);
+        self.text.drain(byte_start..byte_end);
+
+        // Draining shifts the tail left, which leaves the old end of the text behind in the spare capacity
+        let removed = byte_end - byte_start;
+        unsafe { self.text.as_mut_vec() }.spare_capacity_mut()[..removed].zeroize();
+    }
+
+    fn clear(&mut self) {
+        SecretString::clear(self);
+    }
+
+    fn replace_with(&mut self, text: &str) {
+        SecretString::clear(self);
+        self.push_str(text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use egui::TextBuffer;
+
+    #[test]
+    fn test_secret_key() {
+        let mut key = SecretKey::zeroed();
+        assert_eq!(key.expose(), &[0u8; 32]);
+
+        key.expose_mut()[0] = 1;
+        assert_eq!(key.expose()[0], 1);
+
+        // Only 32 byte slices make a key
+        assert!(SecretKey::from_slice(&[0u8; 31]).is_none());
+        assert_eq!(SecretKey::from_slice(&[1u8; 32]).unwrap().expose(), &[1u8; 32]);
+
+        // The key never shows up in debug output
+        assert_eq!(format!("{:?}", key), "SecretKey([REDACTED])");
+    }
+
+    #[test]
+    fn test_secret_string_grows_and_clears() {
+        let mut secret = SecretString::new();
+        let long_text = "a".repeat(INITIAL_CAPACITY * 3);
+
+        // Growing past the initial capacity keeps the contents
+        secret.push_str("supersecret");
+        secret.push_str(&long_text);
+        assert_eq!(secret.expose(), format!("supersecret{}", long_text));
+
+        // Clearing empties the text
+        secret.clear();
+        assert!(secret.expose().is_empty());
+        assert_eq!(format!("{:?}", secret), "SecretString([REDACTED])");
+    }
+
+    #[test]
+    fn test_secret_string_text_buffer() {
+        let mut secret = SecretString::from("secret");
+
+        // Insert and delete the same way egui does when typing
+        secret.insert_text("super", 0);
+        assert_eq!(secret.expose(), "supersecret");
+        secret.delete_char_range(0..5);
+        assert_eq!(secret.expose(), "secret");
+
+        // The bytes that were shifted out of the text are wiped from the spare capacity
+        let spare = unsafe { secret.text.as_mut_vec() }.spare_capacity_mut()[..5].iter().map(|b| unsafe { b.assume_init() }).collect::<Vec<u8>>();
+        assert_eq!(spare, vec![0u8; 5]);
+
+        secret.replace_with("another");
+        assert_eq!(secret.expose(), "another");
+        assert_eq!(secret.clone(), SecretString::from("another"));
+    }
+}
@@ -0,0 +1,1809 @@
+use crate::encryption_algorithms::{encrypt_field, decrypt_field, decrypt_legacy_field, CryptoError};
+use crate::encryption_algorithms::{generate_data_key, wrap_key, unwrap_key};
+use crate::encryption_algorithms::{KdfParams, KDF_ARGON2ID, KDF_LEGACY_SHA256};
+use crate::encryption_algorithms::{derive_key, hash_master};
+use crate::encryption_algorithms::{decrypt_chunk, encrypt_chunk};
+use crate::entry::{Entry, EntryData, EntryDetails, EntryKind, Payload};
+use crate::secret_memory::{SecretKey, SecretString};
+use rand::Rng;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+mod migrations;
+pub use migrations::MigrationError;
+
+/// Number of wrong master passwords allowed before an account starts getting locked
+const FREE_ATTEMPTS: u32 = 3;
+/// The first lockout, every failure after it doubles the wait
+const BASE_LOCKOUT_SECS: u64 = 30;
+/// The longest an account can be locked for at once
+const MAX_LOCKOUT_SECS: u64 = 24 * 60 * 60;
+
+/// Environment variable that picks the vault to open when none is given on the command line
+pub const VAULT_ENV_VAR: &str = "CRYPTKEEPER_VAULT";
+
+/// Where older versions kept the vault, relative to the working directory
+const LEGACY_VAULT_PATH: &str = "storage/passwords.db";
+
+/// A password database file, every account and its entries live in exactly one of these
+/// Separate vaults, for example a personal and a work one, are completely independent files
+/// The vault keeps one connection open for as long as it lives, and caches its prepared statements
+pub struct Vault {
+    path: PathBuf,
+    conn: rusqlite::Connection,
+}
+
+/// Work out which vault to open when no path was given explicitly
+/// The CRYPTKEEPER_VAULT environment variable wins, then the storage/passwords.db file older versions used if there is one
+/// in the working directory, and otherwise passwords.db in the platform data directory
+pub fn default_vault_path() -> PathBuf {
+    if let Some(path) = std::env::var_os(VAULT_ENV_VAR).filter(|path| !path.is_empty()) {
+        return PathBuf::from(path);
+    }
+    if Path::new(LEGACY_VAULT_PATH).exists() {
+        return PathBuf::from(LEGACY_VAULT_PATH);
+    }
+    match dirs::data_dir() {
+        Some(data_dir) => data_dir.join("cryptkeeper").join("passwords.db"),
+        None => PathBuf::from(LEGACY_VAULT_PATH),
+    }
+}
+
+/// Why a vault could not be unlocked
+/// Unknown accounts and wrong passwords give the same error, so the login can't be used to find out which usernames exist
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// Too many wrong attempts, the account is locked for this many more seconds
+    LockedOut(u64),
+    /// The account doesn't exist or the master password is wrong
+    InvalidCredentials,
+    /// The master password couldn't be hashed, or the vault key couldn't be unwrapped
+    Crypto(CryptoError),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::LockedOut(seconds) => write!(f, "account is locked for another {} seconds", seconds),
+            AuthError::InvalidCredentials => write!(f, "incorrect username or master password"),
+            AuthError::Crypto(error) => write!(f, "could not unlock the vault: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<CryptoError> for AuthError {
+    fn from(error: CryptoError) -> Self {
+        AuthError::Crypto(error)
+    }
+}
+
+/// A vault that was unlocked with the right master password
+pub struct UnlockedVault {
+    pub user_id: i32,
+    /// The key that encrypts every entry of the vault
+    pub data_key: SecretKey,
+    /// Why a legacy account couldn't be upgraded to Argon2id, it still unlocked with the legacy key and the upgrade is tried again next time
+    pub upgrade_error: Option<CryptoError>,
+}
+
+/// An entry in the trash, along with when it was deleted in seconds since the unix epoch
+#[derive(Clone, Debug)]
+pub struct TrashedEntry {
+    pub entry: Entry,
+    pub deleted_at: i64,
+}
+
+/// A password an entry had before it was edited
+#[derive(Clone, Debug)]
+pub struct PasswordHistory {
+    pub password: Result<SecretString, CryptoError>,
+    /// When the password was replaced, in seconds since the unix epoch
+    pub changed_at: i64,
+}
+
+/// The score out of 50 from check_password_safety that new master passwords need, unless the vault sets its own
+pub const DEFAULT_MIN_MASTER_SCORE: u32 = 30;
+
+/// How many seconds a revealed password stays shown, unless the vault sets its own
+pub const DEFAULT_REVEAL_TIMEOUT: u32 = 20;
+
+/// When an unlocked vault locks itself again, the same for every account in the vault
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AutoLock {
+    /// Minutes without any input before locking, 0 never locks
+    pub idle_minutes: u32,
+    pub on_focus_loss: bool,
+    pub on_minimize: bool,
+}
+
+impl Default for AutoLock {
+    fn default() -> Self {
+        Self { idle_minutes: 5, on_focus_loss: false, on_minimize: true }
+    }
+}
+
+/// How much of an attachment is encrypted at a time, only two chunks are ever in memory while a file is added
+const ATTACHMENT_CHUNK_SIZE: usize = 64 * 1024;
+/// The largest file that can be attached, the vault is a single SQLite file that is backed up whole
+pub const MAX_ATTACHMENT_SIZE: u64 = 25 * 1024 * 1024;
+
+/// The name and type of an attached file, encrypted together
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AttachmentMetadata {
+    pub filename: SecretString,
+    pub mime: String,
+}
+
+/// A file attached to an entry, its contents are only decrypted when it is exported
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    pub id: i32,
+    pub metadata: Result<AttachmentMetadata, CryptoError>,
+    /// The size of the file in bytes
+    pub size: u64,
+    pub created_at: i64,
+}
+
+/// A folder entries can be filed in, folders can hold other folders
+#[derive(Clone, Debug)]
+pub struct Folder {
+    pub id: i32,
+    /// None for folders at the top level
+    pub parent_id: Option<i32>,
+    pub name: Result<SecretString, CryptoError>,
+}
+
+/// Everything stored about an account that is needed to check its master password and unlock its vault
+struct Credentials {
+    user_id: i32,
+    salt: [u8; 32],
+    kdf_salt: [u8; 32],
+    hashed_master: Vec<u8>,
+    /// None if the account still uses the legacy single SHA-256 hash and needs to be upgraded
+    kdf_params: Option<KdfParams>,
+}
+
+/// The columns decrypt_entry expects, in order, any extra columns a query needs go after them
+const ENTRY_COLUMNS: &str = "entry_id, account, website, password, details, favorite, created_at, modified_at, last_used_at, kind, payload, folder_id";
+
+/// Decrypt an entry from a row that starts with the ENTRY_COLUMNS
+fn decrypt_entry(row: &rusqlite::Row, hashed_master: &SecretKey) -> Entry {
+    let entry_id: i32 = row.get(0).expect("Failed to get entry_id");
+    let encrypted_account: Vec<u8> = row.get(1).expect("Failed to get account");
+    let encrypted_website: Vec<u8> = row.get(2).expect("Failed to get website");
+    let encrypted_password: Vec<u8> = row.get(3).expect("Failed to get password");
+    let encrypted_details: Option<Vec<u8>> = row.get(4).expect("Failed to get details");
+    let kind_code: i32 = row.get(9).expect("Failed to get kind");
+    let encrypted_payload: Option<Vec<u8>> = row.get(10).expect("Failed to get payload");
+
+    // Entries of a kind this version doesn't know are listed as logins, with the payload marked as unreadable
+    let (kind, payload) = match EntryKind::from_code(kind_code) {
+        // Entries from before kinds existed are all logins without a payload
+        Some(kind) => (kind, encrypted_payload.map_or(Ok(Payload::new(kind)), |encrypted| decrypt_payload(&encrypted, hashed_master, entry_id, kind))),
+        None => (EntryKind::Login, Err(CryptoError::UnsupportedVersion)),
+    };
+
+    Entry {
+        id: entry_id,
+        account: decrypt_field(&encrypted_account, hashed_master, entry_id, "account"),
+        website: decrypt_field(&encrypted_website, hashed_master, entry_id, "website"),
+        password: decrypt_field(&encrypted_password, hashed_master, entry_id, "password"),
+        // Entries from before details existed simply don't have any
+        details: encrypted_details.map_or(Ok(EntryDetails::default()), |encrypted| decrypt_json(&encrypted, hashed_master, entry_id, "details")),
+        kind,
+        payload,
+        favorite: row.get(5).expect("Failed to get favorite"),
+        created_at: row.get(6).expect("Failed to get created_at"),
+        modified_at: row.get(7).expect("Failed to get modified_at"),
+        last_used_at: row.get(8).expect("Failed to get last_used_at"),
+        folder_id: row.get(11).expect("Failed to get folder_id"),
+    }
+}
+
+/// Encrypt part of an entry as a JSON document, the plaintext document is wiped once it is encrypted
+fn encrypt_json(value: &impl serde::Serialize, hashed_master: &SecretKey, entry_id: i32, column: &str) -> Vec<u8> {
+    let document = Zeroizing::new(serde_json::to_string(value).expect("Failed to serialize entry"));
+    encrypt_field(&document, hashed_master, entry_id, column)
+}
+
+/// Decrypt part of an entry that was stored as a JSON document
+fn decrypt_json<T: serde::de::DeserializeOwned>(encrypted: &[u8], hashed_master: &SecretKey, entry_id: i32, column: &str) -> Result<T, CryptoError> {
+    let document = decrypt_field(encrypted, hashed_master, entry_id, column)?;
+    serde_json::from_str(document.expose()).map_err(|_| CryptoError::InvalidFormat)
+}
+
+/// Decrypt the payload of an entry, which has to match the kind stored next to it
+fn decrypt_payload(encrypted_payload: &[u8], hashed_master: &SecretKey, entry_id: i32, kind: EntryKind) -> Result<Payload, CryptoError> {
+    let payload: Payload = decrypt_json(encrypted_payload, hashed_master, entry_id, "payload")?;
+    if payload.kind() != kind {
+        return Err(CryptoError::InvalidFormat);
+    }
+    Ok(payload)
+}
+
+/// Write every encrypted column, the favorite flag and the folder of an entry, bound to its entry_id
+fn write_entry(conn: &rusqlite::Connection, entry_id: i32, entry: &EntryData, hashed_master: &SecretKey) -> rusqlite::Result<()> {
+    let encrypted_password = encrypt_field(entry.password.expose(), hashed_master, entry_id, "password");
+    let encrypted_account = encrypt_field(entry.account.expose(), hashed_master, entry_id, "account");
+    let encrypted_website = encrypt_field(entry.website.expose(), hashed_master, entry_id, "website");
+    let encrypted_details = encrypt_json(&entry.details, hashed_master, entry_id, "details");
+    let encrypted_payload = encrypt_json(&entry.payload, hashed_master, entry_id, "payload");
+
+    execute_cached(
+        conn,
+        "UPDATE passwords SET account = ?, password = ?, website = ?, details = ?, kind = ?, payload = ?, favorite = ?, folder_id = ?, modified_at = ? WHERE entry_id = ?",
+        rusqlite::params![
+            encrypted_account, encrypted_password, encrypted_website, encrypted_details,
+            entry.payload.kind().code(), encrypted_payload, entry.favorite, entry.folder_id, unix_now(), entry_id
+        ]
+    )?;
+    Ok(())
+}
+
+/// Read a setting of the vault, None if it was never set
+fn get_setting(conn: &rusqlite::Connection, name: &str) -> Option<String> {
+    conn.prepare_cached("SELECT value FROM settings WHERE name = ?")
+        .expect("Failed to prepare statement")
+        .query_row([name], |row| row.get(0))
+        .ok()
+}
+
+fn set_setting(conn: &rusqlite::Connection, name: &str, value: &str) {
+    execute_cached(
+        conn,
+        "INSERT INTO settings (name, value) VALUES (?, ?) ON CONFLICT (name) DO UPDATE SET value = excluded.value",
+        rusqlite::params![name, value]
+    ).expect("Failed to update setting");
+}
+
+/// Fill a buffer from a reader, only stopping short at the end of the file, returning how much was read
+fn read_chunk(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(error) if error.kind() == ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(filled)
+}
+
+/// Get the user and parent of a folder, None if it doesn't exist
+fn get_folder_owner(conn: &rusqlite::Connection, folder_id: i32) -> rusqlite::Result<Option<(i32, Option<i32>)>> {
+    conn.prepare_cached("SELECT user_id, parent_id FROM folders WHERE folder_id = ?")?
+        .query_row([folder_id], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()
+}
+
+/// Generate the two random salts of an account, one for the verifier hash and one for the key that wraps the vault key
+fn generate_salts() -> ([u8; 32], [u8; 32]) {
+    let salt = rand::thread_rng().gen::<[u8; 32]>();
+    let mut kdf_salt = rand::thread_rng().gen::<[u8; 32]>();
+    while kdf_salt == salt {
+        kdf_salt = rand::thread_rng().gen::<[u8; 32]>();
+    }
+
+    (salt, kdf_salt)
+}
+
+/// Derive the verifier hash and the key wrapping key of a master password with fresh salts
+/// Returns the salts, the verifier and the wrapping key, in that order
+fn derive_master_keys(master_password: &str, kdf_params: KdfParams) -> Result<([u8; 32], [u8; 32], SecretKey, SecretKey), CryptoError> {
+    let (salt, kdf_salt) = generate_salts();
+    let hashed_master = derive_key(master_password, salt, kdf_params)?;
+    let kdf_key = derive_key(master_password, kdf_salt, kdf_params)?;
+
+    Ok((salt, kdf_salt, hashed_master, kdf_key))
+}
+
+/// Get everything needed to check the master password of a user account in one query, None if there is no such account
+fn get_credentials(conn: &rusqlite::Connection, user_account: &str) -> Result<Option<Credentials>, AuthError> {
+    let mut statement = conn.prepare_cached(
+        "SELECT user_id, salt, kdf_salt, hashed_master_password, kdf_algorithm, kdf_memory, kdf_iterations, kdf_parallelism
+            FROM user_id WHERE account = ?"
+    ).expect("Failed to prepare statement");
+    let mut rows = statement.query([&user_account]).unwrap();
+    let Some(row) = rows.next().unwrap() else {
+        return Ok(None);
+    };
+
+    let salt: Option<Vec<u8>> = row.get(1).expect("Failed to get salt");
+    let kdf_salt: Option<Vec<u8>> = row.get(2).expect("Failed to get KDF salt");
+    let kdf_algorithm: i32 = row.get(4).expect("Failed to get KDF algorithm");
+    let (memory_kib, iterations, parallelism): (Option<u32>, Option<u32>, Option<u32>) =
+        (row.get(5).unwrap(), row.get(6).unwrap(), row.get(7).unwrap());
+
+    // An unknown algorithm, missing parameters or a salt of the wrong length mean the row is corrupted or from a newer version of the app
+    let kdf_params = match (kdf_algorithm, memory_kib, iterations, parallelism) {
+        (KDF_LEGACY_SHA256, _, _, _) => None,
+        (KDF_ARGON2ID, Some(memory_kib), Some(iterations), Some(parallelism)) => Some(KdfParams { memory_kib, iterations, parallelism }),
+        _ => return Err(AuthError::Crypto(CryptoError::InvalidKdfParams)),
+    };
+    let (Ok(salt), Ok(kdf_salt)) = (salt.unwrap_or_default().try_into(), kdf_salt.unwrap_or_default().try_into()) else {
+        return Err(AuthError::Crypto(CryptoError::InvalidKdfParams));
+    };
+
+    Ok(Some(Credentials {
+        user_id: row.get(0).expect("Failed to get user_id"),
+        salt,
+        kdf_salt,
+        hashed_master: row.get(3).expect("Failed to get hashed master password"),
+        kdf_params,
+    }))
+}
+
+/// Run a statement through the connection's prepared statement cache, so it is only compiled the first time
+fn execute_cached(conn: &rusqlite::Connection, sql: &str, params: impl rusqlite::Params) -> rusqlite::Result<usize> {
+    conn.prepare_cached(sql)?.execute(params)
+}
+
+/// The current time as seconds since the unix epoch, which is how lockout times are stored
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("System clock is before 1970").as_secs() as i64
+}
+
+/// How long an account is locked for after a given number of consecutive failed attempts
+/// Nothing happens for the first few mistakes, after that the lockout doubles with every failure
+fn lockout_duration(failed_attempts: u32) -> u64 {
+    if failed_attempts < FREE_ATTEMPTS {
+        return 0;
+    }
+    let doublings = failed_attempts - FREE_ATTEMPTS;
+    // Anything past 2^12 is well over the cap anyway, and this keeps the shift from overflowing
+    BASE_LOCKOUT_SECS.saturating_mul(1 << doublings.min(12)).min(MAX_LOCKOUT_SECS)
+}
+
+/// What a login attempt is counted against, usernames without an account are locked out the same way real accounts are
+#[derive(Clone, Copy)]
+enum LoginTarget<'a> {
+    Account(i32),
+    /// The hash of a username that has no account
+    UnknownName(&'a [u8; 32]),
+}
+
+impl LoginTarget<'_> {
+    /// The table the attempts are counted in, along with the column and value that pick out the row
+    fn row(&self) -> (&'static str, &'static str, rusqlite::types::Value) {
+        match self {
+            LoginTarget::Account(user_id) => ("user_id", "user_id", (*user_id).into()),
+            LoginTarget::UnknownName(name_hash) => ("unknown_account_attempts", "name_hash", name_hash.to_vec().into()),
+        }
+    }
+}
+
+/// Hash a username that has no account, so its failed attempts can be counted without storing the name itself
+fn unknown_name_hash(user_account: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"cryptkeeper unknown account\0");
+    hasher.update(user_account.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Get how many seconds are left on the lockout of an account or unknown username at a given time
+fn lockout_remaining_at(conn: &rusqlite::Connection, target: LoginTarget, now: i64) -> u64 {
+    let (table, column, key) = target.row();
+    let mut statement = conn
+        .prepare_cached(&format!("SELECT failed_attempts, locked_until FROM {} WHERE {} = ?", table, column))
+        .expect("Failed to prepare statement");
+    // An unknown username that was never tried has no row yet
+    let (failed_attempts, locked_until): (u32, Option<i64>) = statement
+        .query_row([&key], |row| Ok((row.get(0)?, row.get(1)?)))
+        .optional()
+        .expect("Failed to get lockout")
+        .unwrap_or((0, None));
+
+    let remaining = locked_until.map_or(0, |locked_until| (locked_until - now).max(0) as u64);
+    // If the clock was turned back the lock can't last longer than it was originally set for
+    remaining.min(lockout_duration(failed_attempts))
+}
+
+/// Record a wrong master password, locking the account or unknown username if it has failed too many times in a row
+fn record_failed_attempt(conn: &rusqlite::Connection, target: LoginTarget, now: i64) {
+    let (table, column, key) = target.row();
+    if let LoginTarget::UnknownName(_) = target {
+        execute_cached(conn, "INSERT OR IGNORE INTO unknown_account_attempts (name_hash) VALUES (?)", [&key])
+            .expect("Failed to record failed attempt");
+    }
+    let mut statement = conn
+        .prepare_cached(&format!("SELECT failed_attempts FROM {} WHERE {} = ?", table, column))
+        .expect("Failed to prepare statement");
+    let failed_attempts: u32 = statement.query_row([&key], |row| row.get(0)).expect("Failed to get failed attempts");
+    let failed_attempts = failed_attempts.saturating_add(1);
+
+    let lockout = lockout_duration(failed_attempts);
+    let locked_until = if lockout > 0 { Some(now + lockout as i64) } else { None };
+    execute_cached(
+        conn,
+        &format!("UPDATE {} SET failed_attempts = ?, last_failure = ?, locked_until = ? WHERE {} = ?", table, column),
+        rusqlite::params![failed_attempts, now, locked_until, key]
+    ).expect("Failed to record failed attempt");
+}
+
+/// Forget the failed attempts of a user after they log in successfully
+fn reset_failed_attempts(conn: &rusqlite::Connection, user_id: i32) {
+    execute_cached(
+        conn,
+        "UPDATE user_id SET failed_attempts = 0, last_failure = NULL, locked_until = NULL WHERE user_id = ?",
+        rusqlite::params![user_id]
+    ).expect("Failed to reset failed attempts");
+}
+
+/// Derive the key that wraps the data key of a user whose master password was just verified
+/// Legacy accounts are transparently upgraded to Argon2id here, now that we know the password
+/// If the upgrade fails the legacy key is returned along with the error, the account keeps working and is upgraded on a later login
+fn master_kdf_key(conn: &rusqlite::Connection, credentials: &Credentials, master_password: &str) -> (SecretKey, Option<CryptoError>) {
+    // The parameters were already accepted when checking the verifier, so these can't fail
+    if let Some(kdf_params) = credentials.kdf_params {
+        return (derive_key(master_password, credentials.kdf_salt, kdf_params).expect("Failed to derive key"), None);
+    }
+
+    let old_key = hash_master(master_password, credentials.kdf_salt).expect("Failed to derive key");
+    let kdf_params = KdfParams::default();
+    let new_hashed_master = derive_key(master_password, credentials.salt, kdf_params).expect("Failed to derive key");
+    let new_key = derive_key(master_password, credentials.kdf_salt, kdf_params).expect("Failed to derive key");
+    // If an entry is corrupted the upgrade is rolled back, and the account keeps working with the legacy key
+    match upgrade_master_kdf(conn, credentials.user_id, &old_key, &new_hashed_master, &new_key, kdf_params) {
+        Ok(()) => (new_key, None),
+        Err(error) => (old_key, Some(error)),
+    }
+}
+
+/// Get the wrapped data encryption key of a user, accounts created before the key hierarchy won't have one yet
+fn get_wrapped_key(conn: &rusqlite::Connection, user_id: i32) -> Option<Vec<u8>> {
+    let mut statement = conn.prepare_cached("SELECT wrapped_key FROM user_id WHERE user_id = ?").expect("Failed to prepare statement");
+    statement.query_row([&user_id], |row| row.get(0)).expect("Failed to get wrapped key")
+}
+
+/// Store the wrapped data encryption key of a user
+fn set_wrapped_key(conn: &rusqlite::Connection, user_id: i32, wrapped_key: &[u8]) -> rusqlite::Result<()> {
+    execute_cached(
+        conn,
+        "UPDATE user_id SET wrapped_key = ? WHERE user_id = ?",
+        rusqlite::params![wrapped_key, user_id]
+    )?;
+    Ok(())
+}
+
+/// Give an account that predates the key hierarchy its own data encryption key
+/// Every entry is moved from the key it was encrypted with onto the new data key, which is then wrapped and stored
+/// This should be called inside a transaction
+fn create_data_key(conn: &rusqlite::Connection, user_id: i32, entries_key: &SecretKey, wrapping_key: &SecretKey) -> SecretKey {
+    let data_key = generate_data_key();
+    reencrypt_entries(conn, user_id, entries_key, &data_key);
+    set_wrapped_key(conn, user_id, &wrap_key(&data_key, wrapping_key, user_id)).expect("Failed to update wrapped key");
+
+    data_key
+}
+
+/// Unwrap the data encryption key of a user with their master derived key
+/// Accounts from before the key hierarchy get a data key created here, which reencrypts their entries once
+fn unlock_data_key(conn: &rusqlite::Connection, user_id: i32, kdf_key: &SecretKey) -> Result<SecretKey, CryptoError> {
+    if let Some(wrapped_key) = get_wrapped_key(conn, user_id) {
+        return unwrap_key(&wrapped_key, kdf_key, user_id);
+    }
+
+    // Until now the entries were encrypted directly with the master derived key
+    let transaction = conn.unchecked_transaction().expect("Failed to start transaction");
+    let data_key = create_data_key(&transaction, user_id, kdf_key, kdf_key);
+    transaction.commit().expect("Failed to commit data key");
+
+    Ok(data_key)
+}
+
+/// Upgrade a legacy account to Argon2id
+/// The data encryption key is rewrapped under the new key, accounts without one get one created from their entries,
+/// then the new verifier hash and parameters are stored
+/// This all happens in one transaction so a crash can't leave the vault locked under two different keys
+fn upgrade_master_kdf(conn: &rusqlite::Connection, user_id: i32, old_key: &SecretKey, new_hashed_master: &SecretKey, new_key: &SecretKey, kdf_params: KdfParams) -> Result<(), CryptoError> {
+    let transaction = conn.unchecked_transaction().expect("Failed to start transaction");
+
+    // Rewrap the data key under the new key
+    match get_wrapped_key(&transaction, user_id) {
+        Some(wrapped_key) => {
+            let data_key = unwrap_key(&wrapped_key, old_key, user_id)?;
+            set_wrapped_key(&transaction, user_id, &wrap_key(&data_key, new_key, user_id)).expect("Failed to update wrapped key");
+        }
+        None => {
+            create_data_key(&transaction, user_id, old_key, new_key);
+        }
+    }
+
+    // Store the new verifier hash and the parameters used to derive it
+    execute_cached(
+        &transaction,
+        "UPDATE user_id SET hashed_master_password = ?, kdf_algorithm = ?, kdf_memory = ?, kdf_iterations = ?, kdf_parallelism = ? WHERE user_id = ?",
+        rusqlite::params![new_hashed_master.expose().to_vec(), KDF_ARGON2ID, kdf_params.memory_kib, kdf_params.iterations, kdf_params.parallelism, user_id]
+    ).expect("Failed to update user_id");
+
+    transaction.commit().expect("Failed to commit KDF upgrade");
+
+    Ok(())
+}
+
+/// Whether an account's legacy AES-CBC fields were already moved to envelopes, so CBC must not be read for it anymore
+fn legacy_encryption_migrated(conn: &rusqlite::Connection, user_id: i32) -> bool {
+    conn.prepare_cached("SELECT legacy_encryption_migrated FROM user_id WHERE user_id = ?")
+        .expect("Failed to prepare statement")
+        .query_row([user_id], |row| row.get(0))
+        .expect("Failed to get legacy_encryption_migrated")
+}
+
+/// Decrypt a field while reencrypting, CBC is only read for accounts that haven't been migrated yet
+fn decrypt_for_reencryption(encrypted_data: &[u8], key: &SecretKey, entry_id: i32, column: &str, allow_legacy: bool) -> Result<SecretString, CryptoError> {
+    if allow_legacy {
+        decrypt_legacy_field(encrypted_data, key, entry_id, column).map(|(plaintext, _)| plaintext)
+    } else {
+        decrypt_field(encrypted_data, key, entry_id, column)
+    }
+}
+
+/// Reencrypt every entry of a user from one key to another, matching on the entry_id so each row keeps its own data
+/// Fields that don't decrypt with the old key are corrupted either way, so they are left exactly as they are
+/// Accounts this happens to predate the key hierarchy, so their fields may still be CBC until they are migrated
+/// This should be called inside a transaction
+fn reencrypt_entries(conn: &rusqlite::Connection, user_id: i32, old_key: &SecretKey, new_key: &SecretKey) {
+    let allow_legacy = !legacy_encryption_migrated(conn, user_id);
+    let mut entries = Vec::new();
+    {
+        let mut statement = conn.prepare_cached("SELECT entry_id, account, website, password FROM passwords WHERE user_id = ?").expect("Failed to prepare statement");
+        let mut rows = statement.query([&user_id]).unwrap();
+        while let Some(row) = rows.next().unwrap() {
+            let entry_id: i32 = row.get(0).unwrap();
+            let mut fields = Vec::new();
+            for (index, column) in ["account", "website", "password"].iter().enumerate() {
+                let encrypted_data: Vec<u8> = row.get(index + 1).unwrap();
+                match decrypt_for_reencryption(&encrypted_data, old_key, entry_id, column, allow_legacy) {
+                    Ok(plaintext) => fields.push(encrypt_field(plaintext.expose(), new_key, entry_id, column)),
+                    Err(_) => fields.push(encrypted_data),
+                }
+            }
+            entries.push((entry_id, fields));
+        }
+    }
+
+    for (entry_id, fields) in entries {
+        execute_cached(
+            conn,
+            "UPDATE passwords SET account = ?, website = ?, password = ? WHERE entry_id = ?",
+            rusqlite::params![fields[0], fields[1], fields[2], entry_id]
+        ).expect("Failed to update password");
+    }
+}
+
+/// Reencrypt any legacy AES-CBC fields of a user into authenticated envelopes
+/// This runs on the first login after upgrading, and the account is then marked so CBC is never read for it again
+/// Fields that don't decrypt at all are left alone so they still show up as corrupted instead of being lost
+fn migrate_legacy_encryption(conn: &rusqlite::Connection, user_id: i32, hashed_master: &SecretKey) {
+    if legacy_encryption_migrated(conn, user_id) {
+        return;
+    }
+    let transaction = conn.unchecked_transaction().expect("Failed to start transaction");
+
+    // Find the fields that are not authenticated envelopes yet
+    let mut legacy_fields = Vec::new();
+    {
+        let mut statement = transaction.prepare_cached("SELECT entry_id, account, website, password FROM passwords WHERE user_id = ?").expect("Failed to prepare statement");
+        let mut rows = statement.query([&user_id]).unwrap();
+        while let Some(row) = rows.next().unwrap() {
+            let entry_id: i32 = row.get(0).unwrap();
+            for (index, column) in ["account", "website", "password"].iter().enumerate() {
+                let encrypted_data: Vec<u8> = row.get(index + 1).unwrap();
+                if let Ok((plaintext, true)) = decrypt_legacy_field(&encrypted_data, hashed_master, entry_id, column) {
+                    legacy_fields.push((entry_id, *column, encrypt_field(plaintext.expose(), hashed_master, entry_id, column)));
+                }
+            }
+        }
+    }
+
+    // Write the envelopes back, the column names come from the fixed list above
+    for (entry_id, column, encrypted_data) in legacy_fields {
+        execute_cached(
+            &transaction,
+            &format!("UPDATE passwords SET {} = ? WHERE entry_id = ?", column),
+            rusqlite::params![encrypted_data, entry_id]
+        ).expect("Failed to update password");
+    }
+    transaction.execute("UPDATE user_id SET legacy_encryption_migrated = 1 WHERE user_id = ?", [user_id])
+        .expect("Failed to mark legacy encryption as migrated");
+
+    transaction.commit().expect("Failed to commit encryption migration");
+}
+
+impl Vault {
+    /// Open the vault at path, creating the file and its directory if they don't exist yet
+    /// The database is brought up to the current schema before it is used
+    pub fn open(path: impl Into<PathBuf>) -> Result<Vault, MigrationError> {
+        let path = path.into();
+        if let Some(directory) = path.parent().filter(|directory| !directory.as_os_str().is_empty()) {
+            std::fs::create_dir_all(directory).map_err(MigrationError::Io)?;
+        }
+        migrations::migrate(&path)?;
+
+        let conn = rusqlite::Connection::open(&path)?;
+        // Wait for another process that has the file locked instead of failing straight away
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+
+        Ok(Vault { path, conn })
+    }
+
+    /// The file this vault is stored in
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Creates a new user in the database
+    /// A random data encryption key is generated for the user's vault and stored wrapped by a key derived from the master password
+    pub fn add_user_id(&self, user_account: &str, master_password: &str) -> Result<i32, Error> {
+        let conn = &self.conn;
+
+        // Check to make sure a user account with that name doesn't exist yet
+        let exists: bool = conn.prepare_cached("SELECT EXISTS(SELECT 1 FROM user_id WHERE account = ?)").map_err(Error::other)?
+            .query_row([&user_account], |row| row.get(0))
+            .map_err(Error::other)?;
+        if exists {
+            return Err(Error::new(std::io::ErrorKind::AlreadyExists, "User account already exists"));
+        }
+
+        // Hash the master password for verification, and derive the key that wraps the vault key
+        let kdf_params = KdfParams::default();
+        let (salt, kdf_salt, hashed_master, kdf_key) = derive_master_keys(master_password, kdf_params)
+            .map_err(|error| Error::new(std::io::ErrorKind::InvalidInput, error))?;
+
+        // Add the user to the database and let SQLite pick the user_id
+        let transaction = conn.unchecked_transaction().map_err(Error::other)?;
+        execute_cached(
+            &transaction,
+            "INSERT INTO user_id (account, hashed_master_password, salt, kdf_salt, kdf_algorithm, kdf_memory, kdf_iterations, kdf_parallelism, legacy_encryption_migrated)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, 1)",
+            rusqlite::params![user_account, &hashed_master.expose()[..], salt, kdf_salt,
+                KDF_ARGON2ID, kdf_params.memory_kib, kdf_params.iterations, kdf_params.parallelism]
+        ).map_err(Error::other)?;
+        let user_id = transaction.last_insert_rowid() as i32;
+
+        // Generate the key that will actually encrypt the vault, and wrap it with the master derived key
+        // The wrapped key is bound to the user_id, so it can only be stored once the row exists
+        set_wrapped_key(&transaction, user_id, &wrap_key(&generate_data_key(), &kdf_key, user_id)).map_err(Error::other)?;
+        transaction.commit().map_err(Error::other)?;
+
+        Ok(user_id)
+    }
+
+    /// Check the master password of an account and unlock its vault
+    /// A locked account always gives LockedOut, so it can't be used to test passwords at all,
+    /// and every wrong password is counted in the database so restarting the app doesn't reset the lockout
+    /// Unknown usernames are counted and locked out the same way, and every attempt runs the default key derivation
+    /// before anything else is checked, so neither the result nor the timing gives away which usernames exist
+    /// Legacy accounts are upgraded to Argon2id and legacy fields to authenticated envelopes along the way
+    pub fn verify_master(&self, user_account: &str, master_password: &str) -> Result<UnlockedVault, AuthError> {
+        let conn = &self.conn;
+
+        let credentials = get_credentials(conn, user_account)?;
+        // Every attempt pays for the default key derivation first, whether the account exists, is locked or is a legacy one
+        let salt = credentials.as_ref().map_or([0; 32], |credentials| credentials.salt);
+        let default_hash = derive_key(master_password, salt, KdfParams::default())?;
+
+        let name_hash = unknown_name_hash(user_account);
+        let target = match &credentials {
+            Some(credentials) => LoginTarget::Account(credentials.user_id),
+            None => LoginTarget::UnknownName(&name_hash),
+        };
+        let now = unix_now();
+        let remaining = lockout_remaining_at(conn, target, now);
+        if remaining > 0 {
+            return Err(AuthError::LockedOut(remaining));
+        }
+
+        let Some(credentials) = credentials else {
+            record_failed_attempt(conn, target, now);
+            return Err(AuthError::InvalidCredentials);
+        };
+        let user_id = credentials.user_id;
+
+        // Accounts that haven't been upgraded yet are still verified with the legacy SHA-256 hash
+        let hashed_master = match credentials.kdf_params {
+            Some(kdf_params) if kdf_params == KdfParams::default() => default_hash,
+            Some(kdf_params) => derive_key(master_password, credentials.salt, kdf_params)?,
+            None => hash_master(master_password, credentials.salt)?,
+        };
+        if !bool::from(hashed_master.expose()[..].ct_eq(&credentials.hashed_master[..])) {
+            record_failed_attempt(conn, target, now);
+            return Err(AuthError::InvalidCredentials);
+        }
+        reset_failed_attempts(conn, user_id);
+
+        // The master password only unwraps the data key, which is what actually encrypts the vault
+        let (kdf_key, upgrade_error) = master_kdf_key(conn, &credentials, master_password);
+        let data_key = unlock_data_key(conn, user_id, &kdf_key)?;
+
+        // Move any fields still stored with unauthenticated AES-CBC over to AES-GCM envelopes
+        migrate_legacy_encryption(conn, user_id, &data_key);
+
+        Ok(UnlockedVault { user_id, data_key, upgrade_error })
+    }
+
+    /// Add an entry to the database, returning the entry_id SQLite picked for it
+    pub fn add_entry(&self, user_id: i32, entry: &EntryData, hashed_master: &SecretKey) -> Result<i32, Error> {
+        let conn = &self.conn;
+        let transaction = conn.unchecked_transaction().map_err(Error::other)?;
+
+        // Reserve the entry first so SQLite picks the entry_id, the fields are bound to it so they are encrypted afterwards
+        execute_cached(
+            &transaction,
+            "INSERT INTO passwords (user_id, account, password, website, created_at) VALUES (?, X'', X'', X'', ?)",
+            rusqlite::params![user_id, unix_now()]
+        ).map_err(Error::other)?;
+        let entry_id = transaction.last_insert_rowid() as i32;
+
+        // Encrypt and add the details, nobody ever sees the empty placeholder since this is one transaction
+        write_entry(&transaction, entry_id, entry, hashed_master).map_err(Error::other)?;
+        transaction.commit().map_err(Error::other)?;
+
+        Ok(entry_id)
+    }
+
+    /// Get all of the entries for a user by decrypting all of the data, entries in the trash are left out
+    pub fn get_accounts(&self, hashed_master: &SecretKey, user_id: i32) -> Vec<Entry> {
+        let conn = &self.conn;
+
+        // Filter so only the user's accounts are shown, in the order they were added which the cache relies on
+        let mut entries = Vec::new();
+        let mut statement = conn
+            .prepare_cached(&format!("SELECT {} FROM passwords where user_id = ? AND deleted_at IS NULL ORDER BY entry_id", ENTRY_COLUMNS))
+            .unwrap();
+        let mut rows = statement.query([&user_id]).unwrap();
+
+        // Decrypt the data
+        while let Some(row) = rows.next().unwrap() {
+            entries.push(decrypt_entry(row, hashed_master));
+        }
+
+        entries
+    }
+
+    /// Get a single entry, whether or not it is in the trash
+    pub fn get_entry(&self, hashed_master: &SecretKey, entry_id: i32) -> Option<Entry> {
+        let mut statement = self.conn.prepare_cached(&format!("SELECT {} FROM passwords WHERE entry_id = ?", ENTRY_COLUMNS)).unwrap();
+        let mut rows = statement.query([entry_id]).unwrap();
+
+        rows.next().unwrap().map(|row| decrypt_entry(row, hashed_master))
+    }
+
+    /// Get the entries of a user that are in the trash, most recently deleted first
+    pub fn get_trash(&self, hashed_master: &SecretKey, user_id: i32) -> Vec<TrashedEntry> {
+        let mut statement = self.conn.prepare_cached(&format!(
+            "SELECT {}, deleted_at FROM passwords WHERE user_id = ? AND deleted_at IS NOT NULL ORDER BY deleted_at DESC", ENTRY_COLUMNS
+        )).unwrap();
+        let mut rows = statement.query([&user_id]).unwrap();
+
+        let mut trash = Vec::new();
+        while let Some(row) = rows.next().unwrap() {
+            trash.push(TrashedEntry {
+                entry: decrypt_entry(row, hashed_master),
+                deleted_at: row.get(12).expect("Failed to get deleted_at"),
+            });
+        }
+
+        trash
+    }
+
+    /// Change an entry in place
+    /// If the password changed, the old one is moved to the entry's password history in the same transaction
+    /// Only the user's own entries can be changed, anything else is reported as not found
+    pub fn update_entry(&self, user_id: i32, entry_id: i32, entry: &EntryData, hashed_master: &SecretKey) -> Result<(), Error> {
+        let conn = &self.conn;
+        let transaction = conn.unchecked_transaction().map_err(Error::other)?;
+
+        let encrypted_old_password: Vec<u8> = transaction.prepare_cached("SELECT password FROM passwords WHERE entry_id = ? AND user_id = ?")
+            .map_err(Error::other)?
+            .query_row([entry_id, user_id], |row| row.get(0))
+            .optional()
+            .map_err(Error::other)?
+            .ok_or_else(|| Error::new(std::io::ErrorKind::NotFound, "No such entry"))?;
+
+        // A password that can't be decrypted is lost either way, there is nothing to keep in the history
+        if let Ok(old_password) = decrypt_field(&encrypted_old_password, hashed_master, entry_id, "password") {
+            if old_password != entry.password {
+                // The history row is reserved first so its ciphertext can be bound to its own id, like new entries
+                execute_cached(
+                    &transaction,
+                    "INSERT INTO password_history (entry_id, password, changed_at) VALUES (?, X'', ?)",
+                    rusqlite::params![entry_id, unix_now()]
+                ).map_err(Error::other)?;
+                let history_id = transaction.last_insert_rowid() as i32;
+                let encrypted_history = encrypt_field(old_password.expose(), hashed_master, history_id, "password_history");
+                execute_cached(
+                    &transaction,
+                    "UPDATE password_history SET password = ? WHERE history_id = ?",
+                    rusqlite::params![encrypted_history, history_id]
+                ).map_err(Error::other)?;
+            }
+        }
+
+        // Encrypt the new details, bound to the same entry
+        write_entry(&transaction, entry_id, entry, hashed_master).map_err(Error::other)?;
+        transaction.commit().map_err(Error::other)?;
+
+        Ok(())
+    }
+
+    /// Record that an entry was just used, for example by copying its password
+    pub fn mark_used(&self, entry_id: i32) -> i64 {
+        let now = unix_now();
+        execute_cached(
+            &self.conn,
+            "UPDATE passwords SET last_used_at = ? WHERE entry_id = ?",
+            rusqlite::params![now, entry_id]
+        ).expect("Failed to update last_used_at");
+        now
+    }
+
+    /// Star or unstar an entry, this isn't an edit so the modified time stays the same
+    pub fn set_favorite(&self, entry_id: i32, favorite: bool) {
+        execute_cached(
+            &self.conn,
+            "UPDATE passwords SET favorite = ? WHERE entry_id = ?",
+            rusqlite::params![favorite, entry_id]
+        ).expect("Failed to update favorite");
+    }
+
+    /// Get the earlier passwords of an entry, newest first
+    pub fn get_password_history(&self, entry_id: i32, hashed_master: &SecretKey) -> Vec<PasswordHistory> {
+        let mut statement = self.conn.prepare_cached(
+            "SELECT history_id, password, changed_at FROM password_history WHERE entry_id = ? ORDER BY changed_at DESC, history_id DESC"
+        ).unwrap();
+        let mut rows = statement.query([entry_id]).unwrap();
+
+        let mut history = Vec::new();
+        while let Some(row) = rows.next().unwrap() {
+            let history_id: i32 = row.get(0).expect("Failed to get history_id");
+            let encrypted_password: Vec<u8> = row.get(1).expect("Failed to get password");
+            history.push(PasswordHistory {
+                password: decrypt_field(&encrypted_password, hashed_master, history_id, "password_history"),
+                changed_at: row.get(2).expect("Failed to get changed_at"),
+            });
+        }
+
+        history
+    }
+
+    /// Move one of the user's entries to the trash, returning when it was deleted
+    pub fn trash_entry(&self, user_id: i32, entry_id: i32) -> Result<i64, Error> {
+        let deleted_at = unix_now();
+        let changed = execute_cached(
+            &self.conn,
+            "UPDATE passwords SET deleted_at = ? WHERE entry_id = ? AND user_id = ? AND deleted_at IS NULL",
+            rusqlite::params![deleted_at, entry_id, user_id]
+        ).map_err(Error::other)?;
+
+        if changed == 0 {
+            return Err(Error::new(std::io::ErrorKind::NotFound, "the entry does not exist or is already in the trash"));
+        }
+        Ok(deleted_at)
+    }
+
+    /// Take one of the user's entries back out of the trash
+    pub fn restore_entry(&self, user_id: i32, entry_id: i32) -> Result<(), Error> {
+        let changed = execute_cached(
+            &self.conn,
+            "UPDATE passwords SET deleted_at = NULL WHERE entry_id = ? AND user_id = ? AND deleted_at IS NOT NULL",
+            rusqlite::params![entry_id, user_id]
+        ).map_err(Error::other)?;
+
+        if changed == 0 {
+            return Err(Error::new(std::io::ErrorKind::NotFound, "the entry is not in the trash"));
+        }
+        Ok(())
+    }
+
+    /// Get the score new master passwords need in this vault, it applies to every account so it is read before anyone logs in
+    pub fn min_master_score(&self) -> u32 {
+        get_setting(&self.conn, "min_master_score").and_then(|value| value.parse().ok()).unwrap_or(DEFAULT_MIN_MASTER_SCORE)
+    }
+
+    pub fn set_min_master_score(&self, score: u32) {
+        set_setting(&self.conn, "min_master_score", &score.to_string());
+    }
+
+    /// Get when the vault locks itself, like the minimum master score it is read before anyone logs in
+    pub fn auto_lock(&self) -> AutoLock {
+        let default = AutoLock::default();
+        let setting = |name, default| get_setting(&self.conn, name).and_then(|value| value.parse().ok()).unwrap_or(default);
+        AutoLock {
+            idle_minutes: setting("auto_lock_idle_minutes", default.idle_minutes),
+            on_focus_loss: setting("auto_lock_on_focus_loss", default.on_focus_loss as u32) != 0,
+            on_minimize: setting("auto_lock_on_minimize", default.on_minimize as u32) != 0,
+        }
+    }
+
+    pub fn set_auto_lock(&self, auto_lock: &AutoLock) {
+        set_setting(&self.conn, "auto_lock_idle_minutes", &auto_lock.idle_minutes.to_string());
+        set_setting(&self.conn, "auto_lock_on_focus_loss", &u32::from(auto_lock.on_focus_loss).to_string());
+        set_setting(&self.conn, "auto_lock_on_minimize", &u32::from(auto_lock.on_minimize).to_string());
+    }
+
+    /// Get how many seconds revealed passwords stay shown before they are hidden again, 0 keeps them shown
+    pub fn reveal_timeout(&self) -> u32 {
+        get_setting(&self.conn, "reveal_timeout").and_then(|value| value.parse().ok()).unwrap_or(DEFAULT_REVEAL_TIMEOUT)
+    }
+
+    pub fn set_reveal_timeout(&self, seconds: u32) {
+        set_setting(&self.conn, "reveal_timeout", &seconds.to_string());
+    }
+
+    /// Get how many days entries stay in a user's trash before they are purged, 0 means they are kept until purged by hand
+    pub fn trash_retention_days(&self, user_id: i32) -> u32 {
+        self.conn.prepare_cached("SELECT trash_retention_days FROM user_id WHERE user_id = ?")
+            .expect("Failed to prepare statement")
+            .query_row([user_id], |row| row.get(0))
+            .expect("Failed to get trash_retention_days")
+    }
+
+    /// Set how many days entries stay in a user's trash before they are purged
+    pub fn set_trash_retention_days(&self, user_id: i32, days: u32) {
+        execute_cached(
+            &self.conn,
+            "UPDATE user_id SET trash_retention_days = ? WHERE user_id = ?",
+            rusqlite::params![days, user_id]
+        ).expect("Failed to update trash_retention_days");
+    }
+
+    /// Get how many seconds a user's copied passwords stay in the clipboard, 0 means they are never cleared
+    pub fn clipboard_timeout(&self, user_id: i32) -> u32 {
+        self.conn.prepare_cached("SELECT clipboard_timeout FROM user_id WHERE user_id = ?")
+            .expect("Failed to prepare statement")
+            .query_row([user_id], |row| row.get(0))
+            .expect("Failed to get clipboard_timeout")
+    }
+
+    pub fn set_clipboard_timeout(&self, user_id: i32, seconds: u32) {
+        execute_cached(
+            &self.conn,
+            "UPDATE user_id SET clipboard_timeout = ? WHERE user_id = ?",
+            rusqlite::params![seconds, user_id]
+        ).expect("Failed to update clipboard_timeout");
+    }
+
+    /// Permanently remove every entry that has been in a user's trash for longer than their retention, returning the removed entry_ids
+    pub fn purge_expired_trash(&self, user_id: i32) -> Vec<i32> {
+        let days = self.trash_retention_days(user_id);
+        if days == 0 {
+            return Vec::new();
+        }
+        let cutoff = unix_now() - i64::from(days) * 24 * 60 * 60;
+
+        let expired: Vec<i32> = self.conn.prepare_cached("SELECT entry_id FROM passwords WHERE user_id = ? AND deleted_at <= ?")
+            .expect("Failed to prepare statement")
+            .query_map(rusqlite::params![user_id, cutoff], |row| row.get(0))
+            .expect("Failed to get expired entries")
+            .map(|entry_id| entry_id.expect("Failed to get entry_id"))
+            .collect();
+        for &entry_id in &expired {
+            self.remove_password(user_id, entry_id).expect("Failed to purge password");
+        }
+
+        expired
+    }
+
+    /// Permanently remove a password/account/website triplet from the database by using the unique entry id, along with its password history and attachments
+    /// Only the user's own entries can be removed, anything else is reported as not found
+    pub fn remove_password(&self, user_id: i32, entry_id: i32) -> Result<(), Error> {
+        let conn = &self.conn;
+        let transaction = conn.unchecked_transaction().map_err(Error::other)?;
+
+        // Remove the password
+        let removed = execute_cached(
+            &transaction,
+            "DELETE FROM passwords WHERE entry_id = ? AND user_id = ?",
+            rusqlite::params![entry_id, user_id]
+        ).map_err(Error::other)?;
+        if removed == 0 {
+            return Err(Error::new(std::io::ErrorKind::NotFound, "No such entry"));
+        }
+        execute_cached(
+            &transaction,
+            "DELETE FROM password_history WHERE entry_id = ?",
+            rusqlite::params![entry_id]
+        ).map_err(Error::other)?;
+        execute_cached(
+            &transaction,
+            "DELETE FROM attachment_chunks WHERE attachment_id IN (SELECT attachment_id FROM attachments WHERE entry_id = ?)",
+            rusqlite::params![entry_id]
+        ).map_err(Error::other)?;
+        execute_cached(
+            &transaction,
+            "DELETE FROM attachments WHERE entry_id = ?",
+            rusqlite::params![entry_id]
+        ).map_err(Error::other)?;
+        transaction.commit().map_err(Error::other)?;
+
+        Ok(())
+    }
+
+    /// Add a folder for a user, inside another of their folders or at the top level, returning its folder_id
+    pub fn add_folder(&self, user_id: i32, parent_id: Option<i32>, name: &str, hashed_master: &SecretKey) -> Result<i32, Error> {
+        if let Some(parent_id) = parent_id {
+            if get_folder_owner(&self.conn, parent_id).map_err(Error::other)?.map(|(owner, _)| owner) != Some(user_id) {
+                return Err(Error::new(std::io::ErrorKind::NotFound, "the parent folder does not exist"));
+            }
+        }
+        let transaction = self.conn.unchecked_transaction().map_err(Error::other)?;
+
+        // Reserved first so the name can be bound to the folder_id, like new entries
+        execute_cached(
+            &transaction,
+            "INSERT INTO folders (user_id, parent_id, name) VALUES (?, ?, X'')",
+            rusqlite::params![user_id, parent_id]
+        ).map_err(Error::other)?;
+        let folder_id = transaction.last_insert_rowid() as i32;
+        let encrypted_name = encrypt_field(name, hashed_master, folder_id, "folder_name");
+        execute_cached(
+            &transaction,
+            "UPDATE folders SET name = ? WHERE folder_id = ?",
+            rusqlite::params![encrypted_name, folder_id]
+        ).map_err(Error::other)?;
+        transaction.commit().map_err(Error::other)?;
+
+        Ok(folder_id)
+    }
+
+    /// Get all of the folders of a user, in the order they were made
+    pub fn get_folders(&self, hashed_master: &SecretKey, user_id: i32) -> Vec<Folder> {
+        let mut statement = self.conn.prepare_cached("SELECT folder_id, parent_id, name FROM folders WHERE user_id = ? ORDER BY folder_id").unwrap();
+        let mut rows = statement.query([user_id]).unwrap();
+
+        let mut folders = Vec::new();
+        while let Some(row) = rows.next().unwrap() {
+            let folder_id: i32 = row.get(0).expect("Failed to get folder_id");
+            let encrypted_name: Vec<u8> = row.get(2).expect("Failed to get name");
+            folders.push(Folder {
+                id: folder_id,
+                parent_id: row.get(1).expect("Failed to get parent_id"),
+                name: decrypt_field(&encrypted_name, hashed_master, folder_id, "folder_name"),
+            });
+        }
+
+        folders
+    }
+
+    /// Rename one of the user's folders
+    pub fn rename_folder(&self, user_id: i32, folder_id: i32, name: &str, hashed_master: &SecretKey) -> Result<(), Error> {
+        let encrypted_name = encrypt_field(name, hashed_master, folder_id, "folder_name");
+        let changed = execute_cached(
+            &self.conn,
+            "UPDATE folders SET name = ? WHERE folder_id = ? AND user_id = ?",
+            rusqlite::params![encrypted_name, folder_id, user_id]
+        ).map_err(Error::other)?;
+
+        if changed == 0 {
+            return Err(Error::new(std::io::ErrorKind::NotFound, "the folder does not exist"));
+        }
+        Ok(())
+    }
+
+    /// Move one of the user's folders, along with everything in it, into another of their folders or to the top level
+    /// A folder can't be moved into itself or one of its own subfolders, since that would cut it off from the tree
+    pub fn move_folder(&self, user_id: i32, folder_id: i32, parent_id: Option<i32>) -> Result<(), Error> {
+        match get_folder_owner(&self.conn, folder_id).map_err(Error::other)? {
+            Some((owner, _)) if owner == user_id => {}
+            _ => return Err(Error::new(std::io::ErrorKind::NotFound, "the folder does not exist")),
+        }
+
+        // Walk up from the new parent, which must not pass through the folder being moved
+        let mut ancestor = parent_id;
+        while let Some(ancestor_id) = ancestor {
+            if ancestor_id == folder_id {
+                return Err(Error::new(std::io::ErrorKind::InvalidInput, "a folder can't be moved into itself"));
+            }
+            ancestor = match get_folder_owner(&self.conn, ancestor_id).map_err(Error::other)? {
+                Some((owner, parent)) if owner == user_id => parent,
+                _ => return Err(Error::new(std::io::ErrorKind::NotFound, "the parent folder does not exist")),
+            };
+        }
+
+        execute_cached(
+            &self.conn,
+            "UPDATE folders SET parent_id = ? WHERE folder_id = ? AND user_id = ?",
+            rusqlite::params![parent_id, folder_id, user_id]
+        ).map_err(Error::other)?;
+        Ok(())
+    }
+
+    /// Remove one of the user's folders, the entries and folders in it move up to where the folder was so nothing is lost
+    /// Entries in the trash move too, so restoring them never points at a folder that is gone
+    pub fn remove_folder(&self, user_id: i32, folder_id: i32) -> Result<(), Error> {
+        let parent_id = match get_folder_owner(&self.conn, folder_id).map_err(Error::other)? {
+            Some((owner, parent_id)) if owner == user_id => parent_id,
+            _ => return Err(Error::new(std::io::ErrorKind::NotFound, "the folder does not exist")),
+        };
+        let transaction = self.conn.unchecked_transaction().map_err(Error::other)?;
+
+        execute_cached(
+            &transaction,
+            "UPDATE passwords SET folder_id = ? WHERE folder_id = ?",
+            rusqlite::params![parent_id, folder_id]
+        ).map_err(Error::other)?;
+        execute_cached(
+            &transaction,
+            "UPDATE folders SET parent_id = ? WHERE parent_id = ?",
+            rusqlite::params![parent_id, folder_id]
+        ).map_err(Error::other)?;
+        execute_cached(
+            &transaction,
+            "DELETE FROM folders WHERE folder_id = ?",
+            rusqlite::params![folder_id]
+        ).map_err(Error::other)?;
+        transaction.commit().map_err(Error::other)?;
+
+        Ok(())
+    }
+
+    /// Move one of the user's entries into one of their folders, or out of every folder with None
+    /// This is only filing, so the modified time stays the same
+    pub fn move_entry(&self, user_id: i32, entry_id: i32, folder_id: Option<i32>) -> Result<(), Error> {
+        let changed = execute_cached(
+            &self.conn,
+            "UPDATE passwords SET folder_id = ?1 WHERE entry_id = ?2 AND user_id = ?3
+                AND (?1 IS NULL OR EXISTS (SELECT 1 FROM folders WHERE folder_id = ?1 AND user_id = ?3))",
+            rusqlite::params![folder_id, entry_id, user_id]
+        ).map_err(Error::other)?;
+
+        if changed == 0 {
+            return Err(Error::new(std::io::ErrorKind::NotFound, "the entry or the folder does not exist"));
+        }
+        Ok(())
+    }
+
+    /// Attach a file to an entry, reading and encrypting it one chunk at a time so large files never sit in memory whole
+    /// Nothing is stored if the file is too big or can't be read, since the chunks are only committed once all of them are written
+    /// Files can only be attached to the user's own entries
+    pub fn add_attachment(&self, user_id: i32, entry_id: i32, metadata: &AttachmentMetadata, mut reader: impl Read, hashed_master: &SecretKey) -> Result<Attachment, Error> {
+        let transaction = self.conn.unchecked_transaction().map_err(Error::other)?;
+        let exists = transaction.prepare_cached("SELECT 1 FROM passwords WHERE entry_id = ? AND user_id = ?")
+            .map_err(Error::other)?
+            .exists([entry_id, user_id])
+            .map_err(Error::other)?;
+        if !exists {
+            return Err(Error::new(ErrorKind::NotFound, "the entry does not exist"));
+        }
+
+        // Reserved first so the chunks and metadata can be bound to the attachment_id
+        let created_at = unix_now();
+        execute_cached(
+            &transaction,
+            "INSERT INTO attachments (entry_id, metadata, size, created_at) VALUES (?, X'', 0, ?)",
+            rusqlite::params![entry_id, created_at]
+        ).map_err(Error::other)?;
+        let attachment_id = transaction.last_insert_rowid() as i32;
+
+        // Reading one chunk ahead tells whether the current chunk is the last one, which is sealed into it
+        let mut current = Zeroizing::new(vec![0u8; ATTACHMENT_CHUNK_SIZE]);
+        let mut next = Zeroizing::new(vec![0u8; ATTACHMENT_CHUNK_SIZE]);
+        let mut current_length = read_chunk(&mut reader, &mut current)?;
+        let mut size = current_length as u64;
+        let mut index = 0;
+        loop {
+            let next_length = if current_length == ATTACHMENT_CHUNK_SIZE { read_chunk(&mut reader, &mut next)? } else { 0 };
+            size += next_length as u64;
+            if size > MAX_ATTACHMENT_SIZE {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("attachments can be at most {} MB", MAX_ATTACHMENT_SIZE / 1024 / 1024)));
+            }
+
+            let last = next_length == 0;
+            let encrypted_chunk = encrypt_chunk(&current[..current_length], hashed_master, attachment_id, index, last);
+            execute_cached(
+                &transaction,
+                "INSERT INTO attachment_chunks (attachment_id, chunk_index, data) VALUES (?, ?, ?)",
+                rusqlite::params![attachment_id, index, encrypted_chunk]
+            ).map_err(Error::other)?;
+            if last {
+                break;
+            }
+            std::mem::swap(&mut current, &mut next);
+            current_length = next_length;
+            index += 1;
+        }
+
+        let encrypted_metadata = encrypt_json(metadata, hashed_master, attachment_id, "attachment_metadata");
+        execute_cached(
+            &transaction,
+            "UPDATE attachments SET metadata = ?, size = ? WHERE attachment_id = ?",
+            rusqlite::params![encrypted_metadata, size, attachment_id]
+        ).map_err(Error::other)?;
+        transaction.commit().map_err(Error::other)?;
+
+        Ok(Attachment { id: attachment_id, metadata: Ok(metadata.clone()), size, created_at })
+    }
+
+    /// Get the files attached to an entry, in the order they were added
+    pub fn get_attachments(&self, entry_id: i32, hashed_master: &SecretKey) -> Vec<Attachment> {
+        let mut statement = self.conn.prepare_cached(
+            "SELECT attachment_id, metadata, size, created_at FROM attachments WHERE entry_id = ? ORDER BY attachment_id"
+        ).unwrap();
+        let mut rows = statement.query([entry_id]).unwrap();
+
+        let mut attachments = Vec::new();
+        while let Some(row) = rows.next().unwrap() {
+            let attachment_id: i32 = row.get(0).expect("Failed to get attachment_id");
+            let encrypted_metadata: Vec<u8> = row.get(1).expect("Failed to get metadata");
+            attachments.push(Attachment {
+                id: attachment_id,
+                metadata: decrypt_json(&encrypted_metadata, hashed_master, attachment_id, "attachment_metadata"),
+                size: row.get(2).expect("Failed to get size"),
+                created_at: row.get(3).expect("Failed to get created_at"),
+            });
+        }
+
+        attachments
+    }
+
+    /// Decrypt an attachment into a writer one chunk at a time, returning how many bytes were written
+    /// A chunk that fails to decrypt stops the export with an error, so a tampered file is never passed off as complete
+    /// Only attachments of the user's own entries can be exported
+    pub fn export_attachment(&self, user_id: i32, attachment_id: i32, hashed_master: &SecretKey, mut writer: impl Write) -> Result<u64, Error> {
+        if !self.owns_attachment(user_id, attachment_id).map_err(Error::other)? {
+            return Err(Error::new(ErrorKind::NotFound, "the attachment does not exist"));
+        }
+        let chunk_count: u32 = self.conn.prepare_cached("SELECT COUNT(*) FROM attachment_chunks WHERE attachment_id = ?")
+            .map_err(Error::other)?
+            .query_row([attachment_id], |row| row.get(0))
+            .map_err(Error::other)?;
+        if chunk_count == 0 {
+            return Err(Error::new(ErrorKind::NotFound, "the attachment does not exist"));
+        }
+
+        let mut statement = self.conn.prepare_cached("SELECT data FROM attachment_chunks WHERE attachment_id = ? ORDER BY chunk_index")
+            .map_err(Error::other)?;
+        let mut rows = statement.query([attachment_id]).map_err(Error::other)?;
+
+        // Each chunk is opened for the position it should be at, so missing or reordered chunks fail to decrypt
+        let mut written = 0;
+        let mut index = 0;
+        while let Some(row) = rows.next().map_err(Error::other)? {
+            let encrypted_chunk: Vec<u8> = row.get(0).map_err(Error::other)?;
+            let chunk = decrypt_chunk(&encrypted_chunk, hashed_master, attachment_id, index, index + 1 == chunk_count)
+                .map_err(|error| Error::new(ErrorKind::InvalidData, format!("the attachment could not be decrypted: {}", error)))?;
+            writer.write_all(&chunk)?;
+            written += chunk.len() as u64;
+            index += 1;
+        }
+        writer.flush()?;
+
+        Ok(written)
+    }
+
+    /// Permanently remove an attachment of one of the user's entries
+    pub fn remove_attachment(&self, user_id: i32, attachment_id: i32) -> Result<(), Error> {
+        let transaction = self.conn.unchecked_transaction().map_err(Error::other)?;
+        let changed = execute_cached(
+            &transaction,
+            "DELETE FROM attachments WHERE attachment_id = ? AND entry_id IN (SELECT entry_id FROM passwords WHERE user_id = ?)",
+            rusqlite::params![attachment_id, user_id]
+        ).map_err(Error::other)?;
+        if changed == 0 {
+            return Err(Error::new(ErrorKind::NotFound, "the attachment does not exist"));
+        }
+        execute_cached(
+            &transaction,
+            "DELETE FROM attachment_chunks WHERE attachment_id = ?",
+            rusqlite::params![attachment_id]
+        ).map_err(Error::other)?;
+        transaction.commit().map_err(Error::other)?;
+
+        Ok(())
+    }
+
+    /// Check whether an attachment belongs to one of the user's entries
+    fn owns_attachment(&self, user_id: i32, attachment_id: i32) -> rusqlite::Result<bool> {
+        self.conn.prepare_cached(
+            "SELECT 1 FROM attachments JOIN passwords ON passwords.entry_id = attachments.entry_id WHERE attachment_id = ? AND user_id = ?"
+        )?.exists([attachment_id, user_id])
+    }
+
+    /// Change the master password of a user
+    /// The vault itself stays encrypted with the same data key, so only the wrapped key and the verifier change
+    /// Everything is written in a single UPDATE so the account can't be left half changed
+    pub fn change_master_password(&self, user_id: i32, data_key: &SecretKey, new_master_password: &str) -> Result<(), CryptoError> {
+        // Hash the new master password with fresh salts
+        let kdf_params = KdfParams::default();
+        let (new_salt, new_kdf_salt, new_master_hashed, new_kdf_key) = derive_master_keys(new_master_password, kdf_params)?;
+
+        // Rewrap the data key under the new master derived key
+        let wrapped_key = wrap_key(data_key, &new_kdf_key, user_id);
+
+        // Update the users database
+        execute_cached(
+            &self.conn,
+            "UPDATE user_id SET hashed_master_password = ?, salt = ?, kdf_salt = ?, kdf_algorithm = ?, kdf_memory = ?, kdf_iterations = ?, kdf_parallelism = ?, wrapped_key = ? WHERE user_id = ?",
+            rusqlite::params![&new_master_hashed.expose()[..], new_salt, new_kdf_salt, KDF_ARGON2ID, kdf_params.memory_kib, kdf_params.iterations, kdf_params.parallelism, wrapped_key, user_id]
+        ).expect("Failed to update user_id");
+
+        Ok(())
+    }
+}
+
+/// Vaults for the tests of the storage and of everything built on it
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+
+    /// Open a fresh vault in a temporary directory, the directory has to be kept alive for as long as the vault is used
+    pub(crate) fn test_vault() -> (tempfile::TempDir, Vault) {
+        let directory = tempfile::tempdir().unwrap();
+        let vault = Vault::open(directory.path().join("vault").join("passwords.db")).unwrap();
+        (directory, vault)
+    }
+
+    /// Open a fresh vault with an account called user that is already unlocked, its master password is supersecret
+    pub(crate) fn unlocked_test_vault() -> (tempfile::TempDir, Vault, UnlockedVault) {
+        let (directory, vault) = test_vault();
+        vault.add_user_id("user", "supersecret").unwrap();
+        let unlocked = vault.verify_master("user", "supersecret").unwrap();
+        (directory, vault, unlocked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_support::{test_vault, unlocked_test_vault};
+    use crate::entry::{CardFields, CustomField};
+    use crate::otp::OtpParams;
+
+    #[test]
+    fn test_lockout_duration() {
+        // The first few mistakes are free
+        for failed_attempts in 0..FREE_ATTEMPTS {
+            assert_eq!(lockout_duration(failed_attempts), 0);
+        }
+
+        // After that the lockout doubles every time
+        assert_eq!(lockout_duration(FREE_ATTEMPTS), BASE_LOCKOUT_SECS);
+        assert_eq!(lockout_duration(FREE_ATTEMPTS + 1), BASE_LOCKOUT_SECS * 2);
+        assert_eq!(lockout_duration(FREE_ATTEMPTS + 2), BASE_LOCKOUT_SECS * 4);
+
+        // And is capped, even for absurd numbers of attempts
+        assert_eq!(lockout_duration(FREE_ATTEMPTS + 20), MAX_LOCKOUT_SECS);
+        assert_eq!(lockout_duration(u32::MAX), MAX_LOCKOUT_SECS);
+    }
+
+    #[test]
+    fn test_lockout_is_persisted() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE user_id (user_id INTEGER PRIMARY KEY, failed_attempts INTEGER NOT NULL DEFAULT 0, last_failure INTEGER, locked_until INTEGER)",
+            [],
+        ).unwrap();
+        conn.execute("INSERT INTO user_id (user_id) VALUES (1)", []).unwrap();
+        let now = 1_000_000;
+
+        // No lock until the free attempts are used up
+        let account = LoginTarget::Account(1);
+        for _ in 0..FREE_ATTEMPTS - 1 {
+            record_failed_attempt(&conn, account, now);
+        }
+        assert_eq!(lockout_remaining_at(&conn, account, now), 0);
+
+        // The next failure locks the account, and the lock runs out on its own
+        record_failed_attempt(&conn, account, now);
+        assert_eq!(lockout_remaining_at(&conn, account, now), BASE_LOCKOUT_SECS);
+        assert_eq!(lockout_remaining_at(&conn, account, now + 10), BASE_LOCKOUT_SECS - 10);
+        assert_eq!(lockout_remaining_at(&conn, account, now + BASE_LOCKOUT_SECS as i64), 0);
+
+        // Turning the clock back doesn't make the lock longer than it was set for
+        assert_eq!(lockout_remaining_at(&conn, account, now - 1_000), BASE_LOCKOUT_SECS);
+
+        // A successful login clears everything
+        reset_failed_attempts(&conn, 1);
+        assert_eq!(lockout_remaining_at(&conn, account, now), 0);
+    }
+
+    #[test]
+    fn test_unknown_names_are_locked_out() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE unknown_account_attempts (name_hash BLOB PRIMARY KEY, failed_attempts INTEGER NOT NULL DEFAULT 0, last_failure INTEGER, locked_until INTEGER)",
+            [],
+        ).unwrap();
+        let now = 1_000_000;
+        let name_hash = unknown_name_hash("nobody");
+        let unknown = LoginTarget::UnknownName(&name_hash);
+
+        // A name that was never tried isn't locked, and gets locked after the same number of failures as a real account
+        assert_eq!(lockout_remaining_at(&conn, unknown, now), 0);
+        for _ in 0..FREE_ATTEMPTS {
+            record_failed_attempt(&conn, unknown, now);
+        }
+        assert_eq!(lockout_remaining_at(&conn, unknown, now), BASE_LOCKOUT_SECS);
+
+        // Other names aren't affected, and only the hash of the name is kept
+        let other_hash = unknown_name_hash("somebody");
+        assert_eq!(lockout_remaining_at(&conn, LoginTarget::UnknownName(&other_hash), now), 0);
+        let stored: Vec<u8> = conn.query_row("SELECT name_hash FROM unknown_account_attempts", [], |row| row.get(0)).unwrap();
+        assert_eq!(stored, name_hash);
+    }
+
+    #[test]
+    fn test_vault_round_trip() {
+        let (_directory, vault) = test_vault();
+        let user_id = vault.add_user_id("user", "supersecret").unwrap();
+        assert_eq!(vault.add_user_id("user", "anothersecret").unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+
+        // Unlock, add an entry and read it back
+        let unlocked = vault.verify_master("user", "supersecret").unwrap();
+        assert_eq!(unlocked.user_id, user_id);
+        let entry_id = vault.add_entry(user_id, &EntryData::new("account", "password", "website"), &unlocked.data_key).unwrap();
+        let entries = vault.get_accounts(&unlocked.data_key, user_id);
+        assert_eq!(entries[0].id, entry_id);
+        assert_eq!(entries[0].account.as_ref().unwrap().expose(), "account");
+        assert_eq!(entries[0].website.as_ref().unwrap().expose(), "website");
+        assert_eq!(entries[0].password.as_ref().unwrap().expose(), "password");
+
+        // After changing the master password only the new one works, and the entries are still readable
+        vault.change_master_password(user_id, &unlocked.data_key, "newsecret").unwrap();
+        assert_eq!(vault.verify_master("user", "supersecret").err(), Some(AuthError::InvalidCredentials));
+        let unlocked = vault.verify_master("user", "newsecret").unwrap();
+        assert_eq!(vault.get_accounts(&unlocked.data_key, user_id)[0].password.as_ref().unwrap().expose(), "password");
+
+        // Remove the entry again
+        vault.remove_password(user_id, entry_id).unwrap();
+        assert!(vault.get_accounts(&unlocked.data_key, user_id).is_empty());
+    }
+
+    #[test]
+    fn test_update_entry_keeps_history() {
+        let (_directory, vault, unlocked) = unlocked_test_vault();
+        let user_id = unlocked.user_id;
+        let entry_id = vault.add_entry(user_id, &EntryData::new("account", "first", "website"), &unlocked.data_key).unwrap();
+
+        // Changing only the account doesn't add to the history
+        vault.update_entry(user_id, entry_id, &EntryData::new("renamed", "first", "website"), &unlocked.data_key).unwrap();
+        assert!(vault.get_password_history(entry_id, &unlocked.data_key).is_empty());
+
+        // Changing the password keeps the old ones, newest first
+        vault.update_entry(user_id, entry_id, &EntryData::new("renamed", "second", "website"), &unlocked.data_key).unwrap();
+        vault.update_entry(user_id, entry_id, &EntryData::new("renamed", "third", "website"), &unlocked.data_key).unwrap();
+        let entries = vault.get_accounts(&unlocked.data_key, user_id);
+        assert_eq!((entries.len(), entries[0].id), (1, entry_id));
+        assert_eq!(entries[0].account.as_ref().unwrap().expose(), "renamed");
+        assert_eq!(entries[0].password.as_ref().unwrap().expose(), "third");
+        let history: Vec<String> = vault.get_password_history(entry_id, &unlocked.data_key).iter()
+            .map(|old| old.password.as_ref().unwrap().expose().to_string())
+            .collect();
+        assert_eq!(history, vec!["second", "first"]);
+
+        // Updating an entry that doesn't exist is an error, and removing an entry takes its history with it
+        assert_eq!(vault.update_entry(user_id, entry_id + 1, &EntryData::new("a", "b", "c"), &unlocked.data_key).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        let other_id = vault.add_user_id("other", "othersecret").unwrap();
+        assert_eq!(vault.update_entry(other_id, entry_id, &EntryData::new("a", "b", "c"), &unlocked.data_key).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        vault.remove_password(user_id, entry_id).unwrap();
+        assert!(vault.get_password_history(entry_id, &unlocked.data_key).is_empty());
+    }
+
+    #[test]
+    fn test_entry_details() {
+        let (_directory, vault, unlocked) = unlocked_test_vault();
+        let user_id = unlocked.user_id;
+
+        let mut data = EntryData::new("account", "password", "website");
+        data.details.title = SecretString::from("Title");
+        data.details.notes = SecretString::from("Some notes");
+        data.details.urls = vec![SecretString::from("https://login.example.com")];
+        data.details.tags = vec![SecretString::from("work")];
+        data.details.custom_fields = vec![CustomField { name: SecretString::from("PIN"), value: SecretString::from("1234"), hidden: true }];
+        data.details.otp = Some(OtpParams::from_uri("otpauth://totp/Example:account?secret=GEZDGNBVGY3TQOJQ").unwrap());
+        data.favorite = true;
+        let entry_id = vault.add_entry(user_id, &data, &unlocked.data_key).unwrap();
+
+        // Everything comes back, and the new entry has its timestamps
+        let entry = vault.get_entry(&unlocked.data_key, entry_id).unwrap();
+        assert_eq!(entry.data().unwrap(), data);
+        assert!(entry.created_at.is_some() && entry.modified_at.is_some());
+        assert_eq!(entry.last_used_at, None);
+        assert_eq!(entry.display_name(), Some("Title"));
+
+        // Using and starring an entry are recorded without counting as edits
+        let used_at = vault.mark_used(entry_id);
+        vault.set_favorite(entry_id, false);
+        let entry = vault.get_entry(&unlocked.data_key, entry_id).unwrap();
+        assert_eq!((entry.last_used_at, entry.favorite), (Some(used_at), false));
+
+        // Entries from before details existed still load, with empty details
+        vault.conn.execute("UPDATE passwords SET details = NULL, created_at = NULL WHERE entry_id = ?", [entry_id]).unwrap();
+        let entry = vault.get_entry(&unlocked.data_key, entry_id).unwrap();
+        assert_eq!(entry.details.unwrap(), EntryDetails::default());
+        assert_eq!(entry.created_at, None);
+
+        // Details can't be swapped between entries
+        let other_id = vault.add_entry(user_id, &data, &unlocked.data_key).unwrap();
+        vault.conn.execute("UPDATE passwords SET details = (SELECT details FROM passwords WHERE entry_id = ?) WHERE entry_id = ?", [other_id, entry_id]).unwrap();
+        assert_eq!(vault.get_entry(&unlocked.data_key, entry_id).unwrap().details.unwrap_err(), CryptoError::AuthenticationFailed);
+    }
+
+    #[test]
+    fn test_folders() {
+        let (_directory, vault, unlocked) = unlocked_test_vault();
+        let user_id = unlocked.user_id;
+
+        let work = vault.add_folder(user_id, None, "Work", &unlocked.data_key).unwrap();
+        let servers = vault.add_folder(user_id, Some(work), "Servers", &unlocked.data_key).unwrap();
+        let personal = vault.add_folder(user_id, None, "Personal", &unlocked.data_key).unwrap();
+        vault.rename_folder(user_id, personal, "Home", &unlocked.data_key).unwrap();
+        let folders = vault.get_folders(&unlocked.data_key, user_id);
+        let names: Vec<_> = folders.iter().map(|folder| (folder.id, folder.parent_id, folder.name.as_ref().unwrap().expose())).collect();
+        assert_eq!(names, vec![(work, None, "Work"), (servers, Some(work), "Servers"), (personal, None, "Home")]);
+
+        // Entries can be added into a folder and moved between them
+        let mut data = EntryData::new("root", "password", "server");
+        data.folder_id = Some(servers);
+        let entry_id = vault.add_entry(user_id, &data, &unlocked.data_key).unwrap();
+        assert_eq!(vault.get_entry(&unlocked.data_key, entry_id).unwrap().folder_id, Some(servers));
+        vault.move_entry(user_id, entry_id, Some(personal)).unwrap();
+        assert_eq!(vault.get_entry(&unlocked.data_key, entry_id).unwrap().folder_id, Some(personal));
+        assert_eq!(vault.move_entry(user_id, entry_id, Some(1000)).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        // Folders can't end up inside themselves
+        assert_eq!(vault.move_folder(user_id, work, Some(servers)).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(vault.move_folder(user_id, work, Some(work)).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        vault.move_folder(user_id, personal, Some(servers)).unwrap();
+
+        // Removing a folder moves what was in it up a level
+        vault.remove_folder(user_id, servers).unwrap();
+        let folders = vault.get_folders(&unlocked.data_key, user_id);
+        assert_eq!(folders.iter().map(|folder| (folder.id, folder.parent_id)).collect::<Vec<_>>(), vec![(work, None), (personal, Some(work))]);
+        vault.remove_folder(user_id, personal).unwrap();
+        assert_eq!(vault.get_entry(&unlocked.data_key, entry_id).unwrap().folder_id, Some(work));
+
+        // Other users can't file entries in someone else's folders, or change the folders themselves
+        let other_id = vault.add_user_id("other", "supersecret").unwrap();
+        let other = vault.verify_master("other", "supersecret").unwrap();
+        let other_entry = vault.add_entry(other_id, &EntryData::new("a", "b", "c"), &other.data_key).unwrap();
+        assert!(vault.move_entry(other_id, other_entry, Some(work)).is_err());
+        assert_eq!(vault.move_entry(other_id, entry_id, None).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert!(vault.add_folder(other_id, Some(work), "Mine", &other.data_key).is_err());
+        assert_eq!(vault.rename_folder(other_id, work, "Mine", &other.data_key).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(vault.move_folder(other_id, work, None).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(vault.remove_folder(other_id, work).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        // Names are bound to their folder
+        let new_folder = vault.add_folder(user_id, None, "New", &unlocked.data_key).unwrap();
+        vault.conn.execute("UPDATE folders SET name = (SELECT name FROM folders WHERE folder_id = ?) WHERE folder_id = ?", [work, new_folder]).unwrap();
+        let folders = vault.get_folders(&unlocked.data_key, user_id);
+        assert_eq!(folders.last().unwrap().name.as_ref().unwrap_err(), &CryptoError::AuthenticationFailed);
+    }
+
+    #[test]
+    fn test_attachments() {
+        let (_directory, vault, unlocked) = unlocked_test_vault();
+        let user_id = unlocked.user_id;
+        let entry_id = vault.add_entry(user_id, &EntryData::new("account", "password", "website"), &unlocked.data_key).unwrap();
+        let metadata = AttachmentMetadata { filename: SecretString::from("codes.pdf"), mime: "application/pdf".to_string() };
+
+        // Sizes around the chunk size, including an empty file and one that fills its last chunk exactly
+        for size in [0, 10, ATTACHMENT_CHUNK_SIZE, 2 * ATTACHMENT_CHUNK_SIZE + 5] {
+            let contents: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let attachment = vault.add_attachment(user_id, entry_id, &metadata, contents.as_slice(), &unlocked.data_key).unwrap();
+            assert_eq!(attachment.size, size as u64);
+
+            let mut exported = Vec::new();
+            assert_eq!(vault.export_attachment(user_id, attachment.id, &unlocked.data_key, &mut exported).unwrap(), size as u64);
+            assert_eq!(exported, contents);
+        }
+        let attachments = vault.get_attachments(entry_id, &unlocked.data_key);
+        assert_eq!(attachments.len(), 4);
+        assert_eq!(attachments[0].metadata.as_ref().unwrap(), &metadata);
+
+        // A missing chunk is caught instead of exporting a shorter file
+        let largest = attachments[3].id;
+        vault.conn.execute("DELETE FROM attachment_chunks WHERE attachment_id = ? AND chunk_index = 2", [largest]).unwrap();
+        assert_eq!(vault.export_attachment(user_id, largest, &unlocked.data_key, std::io::sink()).unwrap_err().kind(), ErrorKind::InvalidData);
+        vault.remove_attachment(user_id, largest).unwrap();
+        assert_eq!(vault.export_attachment(user_id, largest, &unlocked.data_key, std::io::sink()).unwrap_err().kind(), ErrorKind::NotFound);
+
+        // Other users can't read, remove or add to the attachments of someone else's entries
+        let other_id = vault.add_user_id("other", "othersecret").unwrap();
+        let first = attachments[0].id;
+        assert_eq!(vault.export_attachment(other_id, first, &unlocked.data_key, std::io::sink()).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(vault.remove_attachment(other_id, first).unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(vault.add_attachment(other_id, entry_id, &metadata, &b"mine"[..], &unlocked.data_key).unwrap_err().kind(), ErrorKind::NotFound);
+
+        // Files over the limit are refused without leaving anything behind
+        let too_big = std::io::repeat(0).take(MAX_ATTACHMENT_SIZE + 1);
+        assert_eq!(vault.add_attachment(user_id, entry_id, &metadata, too_big, &unlocked.data_key).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(vault.get_attachments(entry_id, &unlocked.data_key).len(), 3);
+
+        // Purging the entry takes its attachments with it
+        vault.remove_password(user_id, entry_id).unwrap();
+        let chunks: i64 = vault.conn.query_row("SELECT COUNT(*) FROM attachment_chunks", [], |row| row.get(0)).unwrap();
+        assert_eq!(chunks, 0);
+        assert!(vault.get_attachments(entry_id, &unlocked.data_key).is_empty());
+    }
+
+    #[test]
+    fn test_min_master_score() {
+        let (directory, vault) = test_vault();
+        assert_eq!(vault.min_master_score(), DEFAULT_MIN_MASTER_SCORE);
+        vault.set_min_master_score(45);
+        vault.set_min_master_score(40);
+
+        // The setting belongs to the vault file, so it is there when the vault is opened again
+        let reopened = Vault::open(directory.path().join("vault").join("passwords.db")).unwrap();
+        assert_eq!(reopened.min_master_score(), 40);
+    }
+
+    #[test]
+    fn test_auto_lock() {
+        let (directory, vault) = test_vault();
+        assert_eq!(vault.auto_lock(), AutoLock::default());
+
+        let auto_lock = AutoLock { idle_minutes: 0, on_focus_loss: true, on_minimize: false };
+        vault.set_auto_lock(&auto_lock);
+        let reopened = Vault::open(directory.path().join("vault").join("passwords.db")).unwrap();
+        assert_eq!(reopened.auto_lock(), auto_lock);
+    }
+
+    #[test]
+    fn test_reveal_timeout() {
+        let (_directory, vault) = test_vault();
+        assert_eq!(vault.reveal_timeout(), DEFAULT_REVEAL_TIMEOUT);
+        vault.set_reveal_timeout(0);
+        assert_eq!(vault.reveal_timeout(), 0);
+    }
+
+    #[test]
+    fn test_entry_kinds() {
+        let (_directory, vault, unlocked) = unlocked_test_vault();
+        let user_id = unlocked.user_id;
+
+        let mut data = EntryData::new("", "1234", "");
+        data.payload = Payload::CreditCard(CardFields {
+            cardholder: SecretString::from("A Person"),
+            number: SecretString::from("4111 1111 1111 1111"),
+            expiry: SecretString::from("09/29"),
+            cvv: SecretString::from("123"),
+        });
+        let entry_id = vault.add_entry(user_id, &data, &unlocked.data_key).unwrap();
+
+        let entry = vault.get_entry(&unlocked.data_key, entry_id).unwrap();
+        assert_eq!(entry.kind, EntryKind::CreditCard);
+        assert_eq!(entry.data().unwrap(), data);
+        assert_eq!(entry.display_name(), Some("A Person"));
+
+        // Changing the unencrypted kind doesn't change what the payload is read as
+        vault.conn.execute("UPDATE passwords SET kind = ? WHERE entry_id = ?", [EntryKind::WiFi.code(), entry_id]).unwrap();
+        assert_eq!(vault.get_entry(&unlocked.data_key, entry_id).unwrap().payload.unwrap_err(), CryptoError::InvalidFormat);
+
+        // Kinds from newer versions are kept out of the way rather than misread
+        vault.conn.execute("UPDATE passwords SET kind = 100 WHERE entry_id = ?", [entry_id]).unwrap();
+        let entry = vault.get_entry(&unlocked.data_key, entry_id).unwrap();
+        assert_eq!((entry.kind, entry.payload.unwrap_err()), (EntryKind::Login, CryptoError::UnsupportedVersion));
+    }
+
+    #[test]
+    fn test_trash() {
+        let (_directory, vault, unlocked) = unlocked_test_vault();
+        let user_id = unlocked.user_id;
+        let kept = vault.add_entry(user_id, &EntryData::new("kept", "password", "website"), &unlocked.data_key).unwrap();
+        let deleted = vault.add_entry(user_id, &EntryData::new("deleted", "password", "website"), &unlocked.data_key).unwrap();
+
+        // Trashed entries only show up in the trash, and can't be trashed twice
+        vault.trash_entry(user_id, deleted).unwrap();
+        assert_eq!(vault.trash_entry(user_id, deleted).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(vault.get_accounts(&unlocked.data_key, user_id).iter().map(|entry| entry.id).collect::<Vec<_>>(), vec![kept]);
+        let trash = vault.get_trash(&unlocked.data_key, user_id);
+        assert_eq!(trash[0].entry.id, deleted);
+        assert_eq!(trash[0].entry.account.as_ref().unwrap().expose(), "deleted");
+
+        // Restoring brings it back, entries that aren't in the trash can't be restored
+        vault.restore_entry(user_id, deleted).unwrap();
+        assert_eq!(vault.restore_entry(user_id, kept).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        // Other users can't move the entry in or out of the trash
+        let other_id = vault.add_user_id("other", "othersecret").unwrap();
+        assert_eq!(vault.trash_entry(other_id, kept).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        vault.trash_entry(user_id, kept).unwrap();
+        assert_eq!(vault.restore_entry(other_id, kept).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        vault.restore_entry(user_id, kept).unwrap();
+        assert!(vault.get_trash(&unlocked.data_key, user_id).is_empty());
+        assert_eq!(vault.get_accounts(&unlocked.data_key, user_id).len(), 2);
+    }
+
+    #[test]
+    fn test_clipboard_timeout() {
+        let (_directory, vault) = test_vault();
+        let user_id = vault.add_user_id("user", "supersecret").unwrap();
+        let other_id = vault.add_user_id("other", "supersecret").unwrap();
+        assert_eq!(vault.clipboard_timeout(user_id), 30);
+
+        vault.set_clipboard_timeout(user_id, 0);
+        assert_eq!(vault.clipboard_timeout(user_id), 0);
+        assert_eq!(vault.clipboard_timeout(other_id), 30);
+    }
+
+    #[test]
+    fn test_purge_expired_trash() {
+        let (_directory, vault, unlocked) = unlocked_test_vault();
+        let user_id = unlocked.user_id;
+        let old = vault.add_entry(user_id, &EntryData::new("old", "password", "website"), &unlocked.data_key).unwrap();
+        let recent = vault.add_entry(user_id, &EntryData::new("recent", "password", "website"), &unlocked.data_key).unwrap();
+        assert_eq!(vault.trash_retention_days(user_id), 30);
+
+        // Pretend one entry was deleted 31 days ago
+        vault.trash_entry(user_id, recent).unwrap();
+        vault.trash_entry(user_id, old).unwrap();
+        vault.conn.execute("UPDATE passwords SET deleted_at = ? WHERE entry_id = ?", rusqlite::params![unix_now() - 31 * 24 * 60 * 60, old]).unwrap();
+
+        // With purging turned off nothing goes, otherwise only the expired entry does
+        vault.set_trash_retention_days(user_id, 0);
+        assert!(vault.purge_expired_trash(user_id).is_empty());
+        vault.set_trash_retention_days(user_id, 30);
+        assert_eq!(vault.purge_expired_trash(user_id), vec![old]);
+        let trash = vault.get_trash(&unlocked.data_key, user_id);
+        assert_eq!((trash.len(), trash[0].entry.id), (1, recent));
+    }
+
+    #[test]
+    fn test_identical_entries_are_separate() {
+        let (_directory, vault, unlocked) = unlocked_test_vault();
+        let user_id = unlocked.user_id;
+
+        // Two entries with the same contents still get their own ids, and removing one leaves the other
+        let first = vault.add_entry(user_id, &EntryData::new("account", "password", "website"), &unlocked.data_key).unwrap();
+        let second = vault.add_entry(user_id, &EntryData::new("account", "password", "website"), &unlocked.data_key).unwrap();
+        assert_ne!(first, second);
+        vault.remove_password(user_id, second).unwrap();
+
+        // Another user can't remove the entry, and removing it twice reports it as missing
+        let other_id = vault.add_user_id("other", "othersecret").unwrap();
+        assert_eq!(vault.remove_password(other_id, first).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(vault.remove_password(user_id, second).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        let entries = vault.get_accounts(&unlocked.data_key, user_id);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, first);
+    }
+
+    #[test]
+    fn test_unknown_user_looks_like_wrong_password() {
+        let (_directory, vault) = test_vault();
+        vault.add_user_id("user", "supersecret").unwrap();
+
+        // Unknown usernames are locked out after the same number of failures, so the lockout doesn't give them away either
+        for _ in 0..FREE_ATTEMPTS {
+            assert_eq!(vault.verify_master("user", "wrong").err(), Some(AuthError::InvalidCredentials));
+            assert_eq!(vault.verify_master("nobody", "wrong").err(), Some(AuthError::InvalidCredentials));
+        }
+        for _ in 0..2 {
+            assert!(matches!(vault.verify_master("user", "wrong"), Err(AuthError::LockedOut(_))));
+            assert!(matches!(vault.verify_master("nobody", "wrong"), Err(AuthError::LockedOut(_))));
+        }
+
+        // Only the hash of the unknown name is kept
+        let names: i64 = vault.conn.query_row("SELECT COUNT(*) FROM unknown_account_attempts", [], |row| row.get(0)).unwrap();
+        assert_eq!(names, 1);
+        let stored: Vec<u8> = vault.conn.query_row("SELECT name_hash FROM unknown_account_attempts", [], |row| row.get(0)).unwrap();
+        assert_eq!(stored, unknown_name_hash("nobody"));
+    }
+
+    #[test]
+    fn test_vaults_are_separate() {
+        let (_personal_directory, personal) = test_vault();
+        let (_work_directory, work) = test_vault();
+        personal.add_user_id("user", "supersecret").unwrap();
+
+        // The account only exists in the vault it was created in
+        assert!(personal.verify_master("user", "supersecret").is_ok());
+        assert_eq!(work.verify_master("user", "supersecret").err(), Some(AuthError::InvalidCredentials));
+        assert_eq!(work.path().file_name().unwrap(), "passwords.db");
+    }
+}
@@ -0,0 +1,388 @@
+use rusqlite::Connection;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A single schema upgrade, run inside its own transaction
+type Migration = fn(&Connection) -> rusqlite::Result<()>;
+
+/// Every schema upgrade in order, the schema version of a database is the number of steps that have been applied to it
+/// Steps must never be changed or reordered once released, new changes always go on the end
+const MIGRATIONS: &[Migration] = &[
+    create_tables,
+    add_kdf_columns,
+    add_legacy_encryption_migrated,
+    add_wrapped_key,
+    add_lockout_columns,
+    create_unknown_account_attempts,
+    remove_admin_account,
+    create_password_history,
+    add_trash_columns,
+    add_entry_details,
+    add_entry_kinds,
+    create_folders,
+    create_attachments,
+    create_settings,
+    add_clipboard_timeout,
+];
+
+/// The schema version this build of the app writes
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Everything that can stop a database from being brought up to date
+#[derive(Debug)]
+pub enum MigrationError {
+    /// The database was written by a newer version of the app, opening it could lose data
+    NewerVersion { found: u32, supported: u32 },
+    /// The directory for a new vault could not be created
+    Io(std::io::Error),
+    /// The backup taken before migrating could not be written, so nothing was changed
+    Backup(std::io::Error),
+    /// A migration step failed and was rolled back
+    Sql(rusqlite::Error),
+}
+
+impl std::fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationError::NewerVersion { found, supported } => write!(
+                f,
+                "the password database is on schema version {}, but this version of the app only supports up to {}, please update the app",
+                found, supported
+            ),
+            MigrationError::Io(error) => write!(f, "could not create the vault directory: {}", error),
+            MigrationError::Backup(error) => write!(f, "could not back up the password database before upgrading it: {}", error),
+            MigrationError::Sql(error) => write!(f, "could not upgrade the password database: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(error: rusqlite::Error) -> Self {
+        MigrationError::Sql(error)
+    }
+}
+
+/// Get the schema version recorded in the database header
+fn schema_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// Bring the database file at path up to the current schema
+/// A copy of the file is kept next to it before any step runs, unless it is brand new
+pub fn migrate(path: &Path) -> Result<(), MigrationError> {
+    let mut conn = Connection::open(path)?;
+    let version = schema_version(&conn)?;
+    check_version(version)?;
+
+    if version < SCHEMA_VERSION && has_tables(&conn)? {
+        backup(path, version).map_err(MigrationError::Backup)?;
+    }
+
+    migrate_connection(&mut conn)
+}
+
+/// Refuse databases that were written by a newer version of the app
+fn check_version(version: u32) -> Result<(), MigrationError> {
+    if version > SCHEMA_VERSION {
+        return Err(MigrationError::NewerVersion { found: version, supported: SCHEMA_VERSION });
+    }
+    Ok(())
+}
+
+/// Run every migration step the database hasn't had yet
+/// Each step and the version bump that records it are committed together, so a failure leaves the database on the last good version
+fn migrate_connection(conn: &mut Connection) -> Result<(), MigrationError> {
+    let version = schema_version(conn)?;
+    check_version(version)?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let transaction = conn.transaction()?;
+        migration(&transaction)?;
+        // PRAGMA can't take bound parameters, the version is always a number we computed
+        transaction.execute_batch(&format!("PRAGMA user_version = {}", index + 1))?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Check whether the database has anything in it, a new file doesn't need backing up
+fn has_tables(conn: &Connection) -> rusqlite::Result<bool> {
+    conn.query_row("SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table')", [], |row| row.get(0))
+}
+
+/// Copy the database file next to itself, named after the version it is on and the time of the copy
+/// Nothing has been written through the connection yet, so the file on disk is consistent
+fn backup(path: &Path, version: u32) -> std::io::Result<PathBuf> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+    let mut backup_path = path.as_os_str().to_owned();
+    backup_path.push(format!(".v{}-{}.bak", version, timestamp));
+    let backup_path = PathBuf::from(backup_path);
+
+    std::fs::copy(path, &backup_path)?;
+
+    Ok(backup_path)
+}
+
+/// This function adds a column to an existing table if it isn't there yet
+/// Databases from before schema versioning may already have some of the later columns, since they used to be added on startup
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> rusqlite::Result<()> {
+    let mut statement = conn.prepare(&format!("SELECT COUNT(*) FROM pragma_table_info('{}') WHERE name = ?", table))?;
+    let count: i32 = statement.query_row([column], |row| row.get(0))?;
+
+    if count == 0 {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    Ok(())
+}
+
+/// Version 1, the original users and passwords tables
+/// Databases from before schema versioning already have these, so they are only created if missing
+fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS user_id (
+            user_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            account BLOB NOT NULL,
+            hashed_master_password BLOB NOT NULL,
+            salt BLOB,
+            kdf_salt BLOB
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS passwords (
+            entry_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            account BLOB NOT NULL,
+            password BLOB NOT NULL,
+            website BLOB NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Version 2, Argon2id parameters per account, existing accounts default to the legacy hash
+fn add_kdf_columns(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "user_id", "kdf_algorithm", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "user_id", "kdf_memory", "INTEGER")?;
+    add_column_if_missing(conn, "user_id", "kdf_iterations", "INTEGER")?;
+    add_column_if_missing(conn, "user_id", "kdf_parallelism", "INTEGER")
+}
+
+/// Version 3, whether an account's legacy AES-CBC fields were moved to authenticated envelopes
+/// Accounts from before envelopes are migrated once more on their next login, after which CBC is never read for them
+fn add_legacy_encryption_migrated(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "user_id", "legacy_encryption_migrated", "INTEGER NOT NULL DEFAULT 0")
+}
+
+/// Version 4, the wrapped data encryption key, accounts without one get one the next time they log in
+fn add_wrapped_key(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "user_id", "wrapped_key", "BLOB")
+}
+
+/// Version 5, failed login attempts and lockouts, kept per account so restarting the app doesn't reset them
+fn add_lockout_columns(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "user_id", "failed_attempts", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "user_id", "last_failure", "INTEGER")?;
+    add_column_if_missing(conn, "user_id", "locked_until", "INTEGER")
+}
+
+/// Version 6, failed logins for usernames that have no account, so they get locked out just like real ones
+/// Only a hash of the name is kept, the vault shouldn't collect whatever people typed into the username field
+fn create_unknown_account_attempts(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS unknown_account_attempts (
+            name_hash BLOB PRIMARY KEY,
+            failed_attempts INTEGER NOT NULL DEFAULT 0,
+            last_failure INTEGER,
+            locked_until INTEGER
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Version 7, older versions seeded an admin account with the password admin, which anyone could log in to
+/// Real accounts never get user_id 0, so this only ever removes that seeded row and its placeholder password
+fn remove_admin_account(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM user_id WHERE user_id = 0 AND account = 'admin'", [])?;
+    conn.execute("DELETE FROM passwords WHERE user_id = 0", [])?;
+    Ok(())
+}
+
+/// Version 8, earlier passwords of an entry, kept when the entry is edited so they can be restored
+fn create_password_history(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS password_history (
+            history_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            entry_id INTEGER NOT NULL,
+            password BLOB NOT NULL,
+            changed_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute("CREATE INDEX IF NOT EXISTS password_history_entry ON password_history (entry_id)", [])?;
+    Ok(())
+}
+
+/// Version 9, deleted entries go to the trash first and are purged once they have been there longer than the account's retention
+fn add_trash_columns(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "passwords", "deleted_at", "INTEGER")?;
+    add_column_if_missing(conn, "user_id", "trash_retention_days", "INTEGER NOT NULL DEFAULT 30")
+}
+
+/// Version 10, encrypted details like notes, URLs, tags and custom fields, plus favorites and timestamps
+/// Existing entries get no details and no timestamps, since when they were made was never recorded
+fn add_entry_details(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "passwords", "details", "BLOB")?;
+    add_column_if_missing(conn, "passwords", "favorite", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "passwords", "created_at", "INTEGER")?;
+    add_column_if_missing(conn, "passwords", "modified_at", "INTEGER")?;
+    add_column_if_missing(conn, "passwords", "last_used_at", "INTEGER")
+}
+
+/// Version 11, typed entries like cards and Wi-Fi networks, existing entries are all logins
+fn add_entry_kinds(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "passwords", "kind", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "passwords", "payload", "BLOB")
+}
+
+/// Version 12, nested folders with encrypted names, entries without a folder_id aren't in any folder
+fn create_folders(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS folders (
+            folder_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            parent_id INTEGER,
+            name BLOB NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute("CREATE INDEX IF NOT EXISTS folders_user ON folders (user_id)", [])?;
+    add_column_if_missing(conn, "passwords", "folder_id", "INTEGER")
+}
+
+/// Version 13, encrypted files attached to entries, stored in chunks so they never have to be in memory all at once
+fn create_attachments(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS attachments (
+            attachment_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            entry_id INTEGER NOT NULL,
+            metadata BLOB NOT NULL,
+            size INTEGER NOT NULL,
+            created_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute("CREATE INDEX IF NOT EXISTS attachments_entry ON attachments (entry_id)", [])?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS attachment_chunks (
+            attachment_id INTEGER NOT NULL,
+            chunk_index INTEGER NOT NULL,
+            data BLOB NOT NULL,
+            PRIMARY KEY (attachment_id, chunk_index)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Version 14, settings that apply to the whole vault rather than one account, like the minimum master password strength
+fn create_settings(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute("CREATE TABLE IF NOT EXISTS settings (name TEXT PRIMARY KEY, value TEXT NOT NULL)", [])?;
+    Ok(())
+}
+
+/// Version 15, how many seconds a copied password stays in the clipboard, per account like the trash retention
+fn add_clipboard_timeout(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "user_id", "clipboard_timeout", "INTEGER NOT NULL DEFAULT 30")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Get the column names of a table
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut statement = conn.prepare(&format!("SELECT name FROM pragma_table_info('{}')", table)).unwrap();
+        let names = statement.query_map([], |row| row.get(0)).unwrap();
+        names.map(|name| name.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_migrate_new_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate_connection(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        assert!(columns(&conn, "user_id").contains(&"locked_until".to_string()));
+
+        // Running it again is a no-op
+        migrate_connection(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_migrate_unversioned_database() {
+        // A database from before schema versioning, with the seeded admin account and one column already added on startup
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE user_id (user_id INTEGER PRIMARY KEY, account BLOB NOT NULL, hashed_master_password BLOB NOT NULL,
+                salt BLOB, kdf_salt BLOB, kdf_algorithm INTEGER NOT NULL DEFAULT 0);
+            CREATE TABLE passwords (entry_id INTEGER PRIMARY KEY, user_id INTEGER NOT NULL, account BLOB NOT NULL,
+                password BLOB NOT NULL, website BLOB NOT NULL);
+            INSERT INTO user_id (user_id, account, hashed_master_password) VALUES (0, 'admin', X'00');
+            INSERT INTO user_id (user_id, account, hashed_master_password) VALUES (1, 'user', X'00');
+            INSERT INTO passwords (user_id, account, password, website) VALUES (0, 'admin', X'00', 'admin');
+            INSERT INTO passwords (user_id, account, password, website) VALUES (1, X'01', X'02', X'03');"
+        ).unwrap();
+
+        migrate_connection(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+
+        // The real account and its entry are kept, and get the new columns
+        let user_columns = columns(&conn, "user_id");
+        for column in ["kdf_algorithm", "kdf_memory", "legacy_encryption_migrated", "wrapped_key", "failed_attempts"] {
+            assert!(user_columns.contains(&column.to_string()));
+        }
+        let users: i32 = conn.query_row("SELECT COUNT(*) FROM user_id", [], |row| row.get(0)).unwrap();
+        let entries: i32 = conn.query_row("SELECT COUNT(*) FROM passwords", [], |row| row.get(0)).unwrap();
+        assert_eq!((users, entries), (1, 1));
+    }
+
+    #[test]
+    fn test_refuse_newer_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION + 1)).unwrap();
+
+        assert!(matches!(
+            migrate_connection(&mut conn),
+            Err(MigrationError::NewerVersion { found, supported }) if found == SCHEMA_VERSION + 1 && supported == SCHEMA_VERSION
+        ));
+        // Nothing was created
+        assert!(!has_tables(&conn).unwrap());
+    }
+
+    #[test]
+    fn test_backup_before_migrating() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("passwords.db");
+
+        // A brand new file doesn't need a backup
+        migrate(&path).unwrap();
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 1);
+
+        // An outdated one gets copied before it is changed
+        Connection::open(&path).unwrap().execute_batch("PRAGMA user_version = 2").unwrap();
+        migrate(&path).unwrap();
+        let backups: Vec<PathBuf> = std::fs::read_dir(directory.path()).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|entry| entry.to_string_lossy().ends_with(".bak"))
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(schema_version(&Connection::open(&backups[0]).unwrap()).unwrap(), 2);
+        assert_eq!(schema_version(&Connection::open(&path).unwrap()).unwrap(), SCHEMA_VERSION);
+    }
+}
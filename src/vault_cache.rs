@@ -0,0 +1,470 @@
+use crate::entry::{Entry, EntryData};
+use crate::secret_memory::{SecretKey, SecretString};
+use crate::storage_options_sql::{Attachment, AttachmentMetadata, Folder, PasswordHistory, TrashedEntry, UnlockedVault, Vault};
+use std::collections::HashMap;
+use std::io::{Error, Read, Write};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// What changed in an unlocked vault, sent to everyone subscribed to it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VaultChange {
+    Added,
+    Updated,
+    Trashed,
+    Restored,
+    Purged,
+    /// A folder was added, renamed, moved or removed
+    Folders,
+}
+
+/// A decrypted entry held in memory while the vault is unlocked
+struct CachedEntry {
+    entry: Entry,
+    /// Lowercase copies of the searchable fields, worked out once so searching doesn't allocate per entry
+    search_fields: Vec<SecretString>,
+}
+
+impl CachedEntry {
+    fn new(entry: Entry) -> Self {
+        // Corrupted fields are left out so the entry still shows up when nothing is searched
+        let mut search_fields = Vec::new();
+        for field in [&entry.account, &entry.website].into_iter().flatten() {
+            search_fields.push(SecretString::from(field.expose().to_lowercase()));
+        }
+        if let Ok(details) = &entry.details {
+            for field in std::iter::once(&details.title).chain(&details.urls).chain(&details.tags) {
+                search_fields.push(SecretString::from(field.expose().to_lowercase()));
+            }
+        }
+        // Each kind adds its own fields, like the network name of a Wi-Fi entry
+        if let Ok(payload) = &entry.payload {
+            for field in payload.search_fields() {
+                search_fields.push(SecretString::from(field.expose().to_lowercase()));
+            }
+        }
+
+        Self { entry, search_fields }
+    }
+
+    /// Check whether the title, account, website, a URL, a tag or a searchable field of the kind contains an already lowercased query
+    fn matches(&self, query: &str) -> bool {
+        query.is_empty() || self.search_fields.iter().any(|field| field.expose().contains(query))
+    }
+}
+
+/// The unlocked vault of one user, every entry is decrypted once at unlock and kept in wiping memory
+/// Writes go to SQLite first and are only applied here once they succeed, so the cache never shows anything the database doesn't have
+/// Dropping the cache locks the vault again, wiping the data key and every decrypted field
+pub struct VaultCache {
+    user_id: i32,
+    data_key: SecretKey,
+    entries: Vec<CachedEntry>,
+    trash: Vec<TrashedEntry>,
+    folders: Vec<Folder>,
+    subscribers: Vec<Sender<VaultChange>>,
+}
+
+impl VaultCache {
+    /// Decrypt every entry of an unlocked vault into memory, purging anything that has been in the trash for too long first
+    pub fn load(vault: &Vault, unlocked: UnlockedVault) -> Self {
+        vault.purge_expired_trash(unlocked.user_id);
+        let entries = vault.get_accounts(&unlocked.data_key, unlocked.user_id).into_iter()
+            .map(CachedEntry::new)
+            .collect();
+        let trash = vault.get_trash(&unlocked.data_key, unlocked.user_id);
+        let folders = vault.get_folders(&unlocked.data_key, unlocked.user_id);
+
+        Self { user_id: unlocked.user_id, data_key: unlocked.data_key, entries, trash, folders, subscribers: Vec::new() }
+    }
+
+    pub fn user_id(&self) -> i32 {
+        self.user_id
+    }
+
+    pub fn data_key(&self) -> &SecretKey {
+        &self.data_key
+    }
+
+    /// Get the entry at an index returned by search
+    pub fn entry(&self, index: usize) -> &Entry {
+        &self.entries[index].entry
+    }
+
+    /// Get an entry by its id
+    pub fn find(&self, entry_id: i32) -> Option<&Entry> {
+        self.entries.iter().map(|cached| &cached.entry).find(|entry| entry.id == entry_id)
+    }
+
+    /// Get the entries in the trash, most recently deleted first
+    pub fn trash(&self) -> &[TrashedEntry] {
+        &self.trash
+    }
+
+    /// Get the folders, in the order they were made
+    pub fn folders(&self) -> &[Folder] {
+        &self.folders
+    }
+
+    /// Count how many entries there are outside the trash
+    pub fn entry_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Count the entries in each folder, including the ones in its subfolders
+    pub fn folder_counts(&self) -> HashMap<i32, usize> {
+        let mut counts = HashMap::new();
+        for cached in &self.entries {
+            for folder_id in self.folder_path(cached.entry.folder_id) {
+                *counts.entry(folder_id).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    /// Check whether the entry at an index returned by search is in a folder or one of its subfolders
+    pub fn is_in_folder(&self, index: usize, folder_id: i32) -> bool {
+        self.folder_path(self.entries[index].entry.folder_id).any(|id| id == folder_id)
+    }
+
+    /// Walk from a folder up to the top level, the limit is only there in case the database has a loop
+    fn folder_path(&self, folder_id: Option<i32>) -> impl Iterator<Item = i32> + '_ {
+        std::iter::successors(folder_id, |&id| self.folders.iter().find(|folder| folder.id == id).and_then(|folder| folder.parent_id))
+            .take(self.folders.len() + 1)
+    }
+
+    /// Get a receiver that is sent every change made to the vault from now on
+    pub fn subscribe(&mut self) -> Receiver<VaultChange> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Get the indices of the entries whose title, account, website, URLs, tags or kind specific fields contain the query, ignoring case
+    pub fn search(&self, query: &str) -> Vec<usize> {
+        // The lowercase query is a secret too, so it is wiped once the search is done
+        let query = SecretString::from(query.to_lowercase());
+        self.entries.iter().enumerate()
+            .filter(|(_, entry)| entry.matches(query.expose()))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Add an entry to the database and then to the cache, returning its id
+    pub fn add(&mut self, vault: &Vault, data: &EntryData) -> Result<i32, Error> {
+        let entry_id = vault.add_entry(self.user_id, data, &self.data_key)?;
+
+        // Reading the entry back picks up the timestamps the database set
+        let entry = vault.get_entry(&self.data_key, entry_id).expect("Entry was just added");
+        self.entries.push(CachedEntry::new(entry));
+        self.notify(VaultChange::Added);
+        Ok(entry_id)
+    }
+
+    /// Change an entry in the database and then in the cache, the old password goes to the entry's history
+    pub fn update(&mut self, vault: &Vault, entry_id: i32, data: &EntryData) -> Result<(), Error> {
+        vault.update_entry(self.user_id, entry_id, data, &self.data_key)?;
+        self.reload(vault, entry_id);
+        Ok(())
+    }
+
+    /// Record that an entry was used, for example because its password was copied
+    pub fn mark_used(&mut self, vault: &Vault, entry_id: i32) {
+        let used_at = vault.mark_used(entry_id);
+        if let Some(cached) = self.entries.iter_mut().find(|cached| cached.entry.id == entry_id) {
+            cached.entry.last_used_at = Some(used_at);
+        }
+        self.notify(VaultChange::Updated);
+    }
+
+    /// Star or unstar an entry
+    pub fn set_favorite(&mut self, vault: &Vault, entry_id: i32, favorite: bool) {
+        vault.set_favorite(entry_id, favorite);
+        if let Some(cached) = self.entries.iter_mut().find(|cached| cached.entry.id == entry_id) {
+            cached.entry.favorite = favorite;
+        }
+        self.notify(VaultChange::Updated);
+    }
+
+    /// File an entry in a folder, or take it out of every folder with None
+    pub fn move_entry(&mut self, vault: &Vault, entry_id: i32, folder_id: Option<i32>) -> Result<(), Error> {
+        vault.move_entry(self.user_id, entry_id, folder_id)?;
+        if let Some(cached) = self.entries.iter_mut().find(|cached| cached.entry.id == entry_id) {
+            cached.entry.folder_id = folder_id;
+        }
+        self.notify(VaultChange::Updated);
+        Ok(())
+    }
+
+    /// Add a folder to the database and then to the cache, returning its id
+    pub fn add_folder(&mut self, vault: &Vault, parent_id: Option<i32>, name: &SecretString) -> Result<i32, Error> {
+        let folder_id = vault.add_folder(self.user_id, parent_id, name.expose(), &self.data_key)?;
+        self.folders.push(Folder { id: folder_id, parent_id, name: Ok(name.clone()) });
+        self.notify(VaultChange::Folders);
+        Ok(folder_id)
+    }
+
+    pub fn rename_folder(&mut self, vault: &Vault, folder_id: i32, name: &SecretString) -> Result<(), Error> {
+        vault.rename_folder(self.user_id, folder_id, name.expose(), &self.data_key)?;
+        if let Some(folder) = self.folders.iter_mut().find(|folder| folder.id == folder_id) {
+            folder.name = Ok(name.clone());
+        }
+        self.notify(VaultChange::Folders);
+        Ok(())
+    }
+
+    /// Move a folder into another one or to the top level, refused if that would put it inside itself
+    pub fn move_folder(&mut self, vault: &Vault, folder_id: i32, parent_id: Option<i32>) -> Result<(), Error> {
+        vault.move_folder(self.user_id, folder_id, parent_id)?;
+        if let Some(folder) = self.folders.iter_mut().find(|folder| folder.id == folder_id) {
+            folder.parent_id = parent_id;
+        }
+        self.notify(VaultChange::Folders);
+        Ok(())
+    }
+
+    /// Remove a folder, whatever was in it moves up to where the folder was
+    pub fn remove_folder(&mut self, vault: &Vault, folder_id: i32) -> Result<(), Error> {
+        vault.remove_folder(self.user_id, folder_id)?;
+
+        // The same moves the database just made, so the cache doesn't have to be reloaded
+        let Some(index) = self.folders.iter().position(|folder| folder.id == folder_id) else {
+            return Ok(());
+        };
+        let parent_id = self.folders.remove(index).parent_id;
+        for folder in self.folders.iter_mut().filter(|folder| folder.parent_id == Some(folder_id)) {
+            folder.parent_id = parent_id;
+        }
+        let entries = self.entries.iter_mut().map(|cached| &mut cached.entry);
+        for entry in entries.chain(self.trash.iter_mut().map(|trashed| &mut trashed.entry)) {
+            if entry.folder_id == Some(folder_id) {
+                entry.folder_id = parent_id;
+            }
+        }
+        self.notify(VaultChange::Folders);
+        Ok(())
+    }
+
+    /// Replace the cached copy of an entry with what the database has now
+    fn reload(&mut self, vault: &Vault, entry_id: i32) {
+        if let (Some(cached), Some(entry)) = (
+            self.entries.iter_mut().find(|cached| cached.entry.id == entry_id),
+            vault.get_entry(&self.data_key, entry_id),
+        ) {
+            *cached = CachedEntry::new(entry);
+        }
+        self.notify(VaultChange::Updated);
+    }
+
+    /// Get the earlier passwords of an entry, these are only needed on the edit screen so they are read from the database
+    pub fn password_history(&self, vault: &Vault, entry_id: i32) -> Vec<PasswordHistory> {
+        vault.get_password_history(entry_id, &self.data_key)
+    }
+
+    /// Get the files attached to an entry, like the history they are read from the database when they are needed
+    pub fn attachments(&self, vault: &Vault, entry_id: i32) -> Vec<Attachment> {
+        vault.get_attachments(entry_id, &self.data_key)
+    }
+
+    /// Attach a file to an entry, it is encrypted as it is read
+    pub fn add_attachment(&mut self, vault: &Vault, entry_id: i32, metadata: &AttachmentMetadata, reader: impl Read) -> Result<Attachment, Error> {
+        let attachment = vault.add_attachment(self.user_id, entry_id, metadata, reader, &self.data_key)?;
+        self.notify(VaultChange::Updated);
+        Ok(attachment)
+    }
+
+    /// Decrypt an attachment into a writer, returning how many bytes were written
+    pub fn export_attachment(&self, vault: &Vault, attachment_id: i32, writer: impl Write) -> Result<u64, Error> {
+        vault.export_attachment(self.user_id, attachment_id, &self.data_key, writer)
+    }
+
+    pub fn remove_attachment(&mut self, vault: &Vault, attachment_id: i32) -> Result<(), Error> {
+        vault.remove_attachment(self.user_id, attachment_id)?;
+        self.notify(VaultChange::Updated);
+        Ok(())
+    }
+
+    /// Move an entry to the trash in the database and then in the cache
+    /// Search results are indices, so the ones after it shift down, which subscribers find out about through the notification
+    pub fn trash_entry(&mut self, vault: &Vault, entry_id: i32) -> Result<(), Error> {
+        let deleted_at = vault.trash_entry(self.user_id, entry_id)?;
+
+        if let Some(index) = self.entries.iter().position(|cached| cached.entry.id == entry_id) {
+            let cached = self.entries.remove(index);
+            self.trash.insert(0, TrashedEntry { entry: cached.entry, deleted_at });
+        }
+        self.notify(VaultChange::Trashed);
+        Ok(())
+    }
+
+    /// Take an entry back out of the trash in the database and then in the cache
+    pub fn restore(&mut self, vault: &Vault, entry_id: i32) -> Result<(), Error> {
+        vault.restore_entry(self.user_id, entry_id)?;
+
+        if let Some(index) = self.trash.iter().position(|trashed| trashed.entry.id == entry_id) {
+            let trashed = self.trash.remove(index);
+            // Entries are kept in the order they were added, the same order get_accounts loads them in
+            let position = self.entries.partition_point(|cached| cached.entry.id < entry_id);
+            self.entries.insert(position, CachedEntry::new(trashed.entry));
+        }
+        self.notify(VaultChange::Restored);
+        Ok(())
+    }
+
+    /// Permanently remove an entry that is in the trash
+    pub fn purge(&mut self, vault: &Vault, entry_id: i32) -> Result<(), Error> {
+        vault.remove_password(self.user_id, entry_id)?;
+
+        self.trash.retain(|trashed| trashed.entry.id != entry_id);
+        self.notify(VaultChange::Purged);
+        Ok(())
+    }
+
+    /// Get how many seconds copied passwords stay in the clipboard, 0 means they are never cleared
+    pub fn clipboard_timeout(&self, vault: &Vault) -> u32 {
+        vault.clipboard_timeout(self.user_id)
+    }
+
+    pub fn set_clipboard_timeout(&self, vault: &Vault, seconds: u32) {
+        vault.set_clipboard_timeout(self.user_id, seconds);
+    }
+
+    /// Get how many days entries stay in the trash, 0 means until they are purged by hand
+    pub fn trash_retention_days(&self, vault: &Vault) -> u32 {
+        vault.trash_retention_days(self.user_id)
+    }
+
+    /// Change how many days entries stay in the trash, anything that is now past it is purged straight away
+    pub fn set_trash_retention_days(&mut self, vault: &Vault, days: u32) {
+        vault.set_trash_retention_days(self.user_id, days);
+
+        let purged = vault.purge_expired_trash(self.user_id);
+        if !purged.is_empty() {
+            self.trash.retain(|trashed| !purged.contains(&trashed.entry.id));
+            self.notify(VaultChange::Purged);
+        }
+    }
+
+    /// Tell every subscriber about a change, forgetting the ones that have gone away
+    fn notify(&mut self, change: VaultChange) {
+        self.subscribers.retain(|subscriber| subscriber.send(change).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::{Payload, WifiFields};
+    use crate::storage_options_sql::test_support::unlocked_test_vault;
+
+    /// Load the cache of a fresh vault with one unlocked account, the directory has to be kept alive for as long as the vault is used
+    fn test_cache() -> (tempfile::TempDir, Vault, VaultCache) {
+        let (directory, vault, unlocked) = unlocked_test_vault();
+        let cache = VaultCache::load(&vault, unlocked);
+        (directory, vault, cache)
+    }
+
+    /// Load the vault again from the database, to check the cache wrote everything through
+    fn reload(vault: &Vault) -> VaultCache {
+        VaultCache::load(vault, vault.verify_master("user", "supersecret").unwrap())
+    }
+
+    #[test]
+    fn test_cache_writes_through() {
+        let (_directory, vault, mut cache) = test_cache();
+        let user_id = cache.user_id();
+        let changes = cache.subscribe();
+        assert!(cache.search("").is_empty());
+
+        cache.add(&vault, &EntryData::new("Alice", "hunter2", "Example.com")).unwrap();
+        let mut bob = EntryData::new("bob", "correct horse", "other.org");
+        bob.details.tags = vec![SecretString::from("Work")];
+        cache.add(&vault, &bob).unwrap();
+        assert_eq!(changes.try_iter().collect::<Vec<_>>(), vec![VaultChange::Added, VaultChange::Added]);
+
+        // Searching ignores case and looks at both the account and the website
+        assert_eq!(cache.search("ALICE"), vec![0]);
+        assert_eq!(cache.search("example"), vec![0]);
+        assert_eq!(cache.search(""), vec![0, 1]);
+        assert!(cache.search("nobody").is_empty());
+        assert_eq!(cache.search("work"), vec![1]);
+
+        // Editing keeps the entry where it is and remembers the old password
+        let bob_id = cache.entry(1).id;
+        bob.password = SecretString::from("battery staple");
+        cache.update(&vault, bob_id, &bob).unwrap();
+        assert_eq!(changes.try_recv(), Ok(VaultChange::Updated));
+        assert_eq!(cache.entry(1).password.as_ref().unwrap().expose(), "battery staple");
+        assert_eq!(cache.password_history(&vault, bob_id)[0].password.as_ref().unwrap().expose(), "correct horse");
+
+        // Deleting moves the entry to the trash, from where it can be restored to where it was
+        let alice = cache.entry(0).id;
+        cache.trash_entry(&vault, alice).unwrap();
+        assert_eq!(changes.try_recv(), Ok(VaultChange::Trashed));
+        assert!(cache.find(alice).is_none());
+        assert_eq!(cache.trash()[0].entry.id, alice);
+        cache.restore(&vault, alice).unwrap();
+        assert_eq!(changes.try_recv(), Ok(VaultChange::Restored));
+        assert_eq!(cache.entry(0).id, alice);
+        assert!(cache.trash().is_empty());
+
+        // Purging goes through to the database
+        cache.trash_entry(&vault, alice).unwrap();
+        cache.purge(&vault, alice).unwrap();
+        assert_eq!(changes.try_iter().collect::<Vec<_>>(), vec![VaultChange::Trashed, VaultChange::Purged]);
+        assert!(cache.trash().is_empty());
+        assert_eq!(cache.entry(0).account.as_ref().unwrap().expose(), "bob");
+
+        // A fresh load sees exactly what the cache has
+        let reloaded = reload(&vault);
+        assert_eq!(reloaded.user_id(), user_id);
+        assert_eq!(reloaded.search(""), vec![0]);
+        assert_eq!(reloaded.entry(0).password.as_ref().unwrap().expose(), "battery staple");
+    }
+
+    #[test]
+    fn test_folders() {
+        let (_directory, vault, mut cache) = test_cache();
+        let changes = cache.subscribe();
+
+        let work = cache.add_folder(&vault, None, &SecretString::from("Work")).unwrap();
+        let servers = cache.add_folder(&vault, Some(work), &SecretString::from("Servers")).unwrap();
+        let mut data = EntryData::new("root", "password", "server");
+        data.folder_id = Some(servers);
+        let entry_id = cache.add(&vault, &data).unwrap();
+        cache.add(&vault, &EntryData::new("unfiled", "password", "example.com")).unwrap();
+        assert_eq!(changes.try_iter().collect::<Vec<_>>(), vec![VaultChange::Folders, VaultChange::Folders, VaultChange::Added, VaultChange::Added]);
+
+        // Counts include what is in subfolders
+        assert_eq!(cache.folder_counts(), HashMap::from([(work, 1), (servers, 1)]));
+        assert!(cache.is_in_folder(0, work) && cache.is_in_folder(0, servers));
+        assert!(!cache.is_in_folder(1, work));
+        assert_eq!(cache.entry_count(), 2);
+        cache.move_entry(&vault, entry_id, Some(work)).unwrap();
+        assert_eq!(cache.folder_counts(), HashMap::from([(work, 1)]));
+        cache.move_entry(&vault, entry_id, Some(servers)).unwrap();
+
+        // Removing a folder moves its entries up, just like the database does
+        cache.remove_folder(&vault, servers).unwrap();
+        assert_eq!(cache.find(entry_id).unwrap().folder_id, Some(work));
+        assert!(cache.move_folder(&vault, work, Some(work)).is_err());
+        cache.rename_folder(&vault, work, &SecretString::from("Job")).unwrap();
+
+        let reloaded = reload(&vault);
+        assert_eq!(reloaded.folders().len(), 1);
+        assert_eq!(reloaded.folders()[0].name.as_ref().unwrap().expose(), "Job");
+        assert_eq!(reloaded.find(entry_id).unwrap().folder_id, Some(work));
+    }
+
+    #[test]
+    fn test_search_kind_fields() {
+        let (_directory, vault, mut cache) = test_cache();
+
+        let mut wifi = EntryData::new("", "network password", "");
+        wifi.payload = Payload::WiFi(WifiFields { ssid: SecretString::from("HomeNet"), ..WifiFields::default() });
+        cache.add(&vault, &wifi).unwrap();
+
+        assert_eq!(cache.search("homenet"), vec![0]);
+        // Secrets like the password are never searched
+        assert!(cache.search("network password").is_empty());
+    }
+}